@@ -0,0 +1,87 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+
+use crate::{
+    bundle::{self, Bundle},
+    error::Error,
+};
+
+/// Checks that `dir` holds build output to deploy: the `.js` module generated
+/// by the build and the `.wasm` module it loads.
+pub(crate) fn check(dir: &Path) -> Result<(), anyhow::Error> {
+    if !dir.is_dir() {
+        return Err(Error::MissingFiles {
+            path: dir.to_owned(),
+            description: "build output directory doesn't exist".to_owned(),
+        }
+        .into());
+    }
+
+    let mut has_js = false;
+    let mut has_wasm = false;
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        has_js |= file_name.ends_with(".js") || file_name.ends_with(".mjs");
+        has_wasm |= file_name.ends_with(".wasm") || file_name.ends_with(".wasm.bin");
+    }
+
+    let missing = match (has_js, has_wasm) {
+        (true, true) => return Ok(()),
+        (false, true) => "no .js module in build output",
+        (true, false) => "no .wasm module in build output",
+        (false, false) => "no .js or .wasm modules in build output",
+    };
+    Err(Error::MissingFiles {
+        path: dir.to_owned(),
+        description: missing.to_owned(),
+    }
+    .into())
+}
+
+/// Build output or a bundle to deploy rather than building.
+#[derive(Debug)]
+pub(crate) enum Artifact {
+    /// A directory of build output, deployed in place of `pkg`.
+    BuildOutput(PathBuf),
+    /// A bundle written by `cargo screeps package`, whose modules are deployed
+    /// as they are.
+    Bundle(Box<Bundle>),
+}
+
+/// Opens what's given with `--from`: a directory of build output or a bundle,
+/// either of which can be a `.tar.gz` archive, unpacked under `target/` in
+/// `root`, or a `.json` bundle. Checks build output has the expected modules,
+/// and bundles' modules match their manifest.
+pub(crate) fn open(root: &Path, from: &Path) -> Result<Artifact, anyhow::Error> {
+    let dir = if from.is_dir() {
+        from.canonicalize()?
+    } else if !from.exists() {
+        return Err(Error::MissingFiles {
+            path: from.to_owned(),
+            description: "build output to deploy doesn't exist".to_owned(),
+        }
+        .into());
+    } else if bundle::is_archive(from) {
+        bundle::unpack(from, &root.join("target").join("screeps-artifact"))?
+    } else if from.extension() == Some("json".as_ref()) {
+        return Ok(Artifact::Bundle(Box::new(Bundle::read(from)?)));
+    } else {
+        bail!(
+            "{} isn't a directory, a .tar.gz archive or a .json bundle",
+            from.display()
+        );
+    };
+
+    if dir.join(bundle::BUNDLE_FILE).is_file() {
+        return Ok(Artifact::Bundle(Box::new(Bundle::read(&dir)?)));
+    }
+
+    check(&dir)?;
+
+    Ok(Artifact::BuildOutput(dir))
+}
@@ -0,0 +1,136 @@
+use anyhow::anyhow;
+use log::*;
+use serde_json::json;
+
+use crate::{
+    config::{Authentication, ServerConfiguration},
+    progress,
+    setup::{ActiveBranch, MessageFormat},
+    upload,
+};
+
+pub fn list(
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    message_format: MessageFormat,
+) -> Result<(), anyhow::Error> {
+    let url = server.api_url("user/branches");
+    let client = upload::client(server)?;
+
+    let response = upload::send_with_retries(server, || {
+        upload::authenticate(client.get(&url), authentication)
+    })?;
+    let response_json = upload::api_response(response, "listing branches")?;
+
+    let branches = response_json
+        .get("list")
+        .and_then(serde_json::Value::as_array)
+        .ok_or_else(|| anyhow!("response from '{}' contained no branch list", url))?;
+
+    let mut listed = Vec::new();
+    for branch in branches {
+        let name = branch
+            .get("branch")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default();
+        let is_active = |key| branch.get(key).and_then(serde_json::Value::as_bool) == Some(true);
+
+        let mut active = Vec::new();
+        if is_active("activeWorld") {
+            active.push("world");
+        }
+        if is_active("activeSim") {
+            active.push("sim");
+        }
+
+        match message_format {
+            MessageFormat::Human if active.is_empty() => println!("{name}"),
+            MessageFormat::Human => println!("{name} (active: {})", active.join(", ")),
+            MessageFormat::Json => listed.push(json!({ "name": name, "active": active })),
+        }
+    }
+
+    if message_format == MessageFormat::Json {
+        progress::print_json(&json!({ "reason": "branch-list", "branches": listed }));
+    }
+
+    Ok(())
+}
+
+pub fn clone(
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    branch: &str,
+    new_name: &str,
+) -> Result<(), anyhow::Error> {
+    post(
+        authentication,
+        server,
+        "user/clone-branch",
+        json!({ "branch": branch, "newName": new_name }),
+        &format!("cloning branch '{branch}' to '{new_name}'"),
+    )?;
+    info!("cloned branch '{}' to '{}'", branch, new_name);
+
+    Ok(())
+}
+
+pub fn delete(
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    branch: &str,
+) -> Result<(), anyhow::Error> {
+    post(
+        authentication,
+        server,
+        "user/delete-branch",
+        json!({ "branch": branch }),
+        &format!("deleting branch '{branch}'"),
+    )?;
+    info!("deleted branch '{}'", branch);
+
+    Ok(())
+}
+
+pub fn activate(
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    branch: &str,
+    target: ActiveBranch,
+) -> Result<(), anyhow::Error> {
+    let (active_name, description) = match target {
+        ActiveBranch::World => ("activeWorld", "world"),
+        ActiveBranch::Sim => ("activeSim", "simulation"),
+    };
+
+    post(
+        authentication,
+        server,
+        "user/set-active-branch",
+        json!({ "branch": branch, "activeName": active_name }),
+        &format!("activating branch '{branch}'"),
+    )?;
+    info!(
+        "branch '{}' is now the active {} branch",
+        branch, description
+    );
+
+    Ok(())
+}
+
+fn post(
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    endpoint: &str,
+    body: serde_json::Value,
+    action: &str,
+) -> Result<serde_json::Value, anyhow::Error> {
+    let url = server.api_url(endpoint);
+    let client = upload::client(server)?;
+
+    let response = upload::send_with_retries(server, || {
+        upload::authenticate(client.post(&url), authentication).json(&body)
+    })?;
+
+    upload::api_response(response, action)
+}
@@ -0,0 +1,99 @@
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, bail, Context};
+use log::*;
+
+use crate::{
+    config::{WasmOptConfiguration, WasmOptLevel},
+    progress::{self, Event},
+};
+
+/// Runs `wasm-opt` on each wasm file in the output directory, if enabled.
+///
+/// The name section is kept, so it can be saved to the symbol map; it's
+/// removed afterwards if `strip_name_section` is set.
+pub fn optimize(output_dir: &Path, config: &WasmOptConfiguration) -> Result<(), anyhow::Error> {
+    if !config.enabled.unwrap_or(false) {
+        return Ok(());
+    }
+
+    let binary = config
+        .binary
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("wasm-opt"));
+
+    // collect paths first, so the optimized files written alongside aren't picked up
+    let mut wasm_paths = Vec::new();
+    for entry in fs::read_dir(output_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(OsStr::to_str) == Some("wasm") {
+            wasm_paths.push(path);
+        }
+    }
+
+    for wasm_path in wasm_paths {
+        let size_before = fs::metadata(&wasm_path)?.len();
+        let optimized_path = wasm_path.with_extension("wasm-opt.wasm");
+
+        let mut command = process::Command::new(&binary);
+        command
+            .arg(config.level.unwrap_or(WasmOptLevel::Os).flag())
+            .args(config.features.iter().map(|f| format!("--enable-{f}")))
+            .args(config.passes.iter().map(|p| format!("--{p}")))
+            .arg("-g")
+            .arg(&wasm_path)
+            .arg("-o")
+            .arg(&optimized_path);
+
+        debug!("running {:?}", command);
+        let status = match command.status() {
+            Ok(status) => status,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if config.skip_if_missing.unwrap_or(false) {
+                    warn!(
+                        "'{}' not found, leaving {} unoptimized",
+                        binary.display(),
+                        wasm_path.display()
+                    );
+                    return Ok(());
+                }
+                bail!(
+                    "'{}' not found; install binaryen (https://github.com/WebAssembly/binaryen) \
+                     and make sure wasm-opt is on your PATH, set 'binary' in [build.wasm_opt] to \
+                     its location, or set 'skip_if_missing = true' to build without it",
+                    binary.display()
+                );
+            }
+            Err(e) => {
+                return Err(anyhow!(e).context(format!("running '{}'", binary.display())));
+            }
+        };
+        if !status.success() {
+            // don't leave a half-written module around to be picked up by later steps
+            let _ = fs::remove_file(&optimized_path);
+            bail!(
+                "'{}' failed on {} with {}",
+                binary.display(),
+                wasm_path.display(),
+                status
+            );
+        }
+
+        fs::rename(&optimized_path, &wasm_path)
+            .with_context(|| format!("replacing {}", wasm_path.display()))?;
+
+        let size_after = fs::metadata(&wasm_path)?.len();
+        progress::emit(Event::WasmOptimized {
+            path: wasm_path,
+            size_before,
+            size_after,
+        });
+    }
+
+    Ok(())
+}
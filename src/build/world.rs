@@ -1,10 +1,15 @@
-use std::{env, ffi::OsStr, fs, io::Write, path::Path};
+use std::{env, ffi::OsStr, fs, io::Write, path::Path, sync::LazyLock};
 
-use anyhow::{anyhow, ensure};
+use anyhow::{ensure, Context};
 use log::*;
+use regex::Regex;
 use wasm_pack::command::build::{Build, BuildOptions, Target};
 
-use crate::config::{BuildConfiguration, BuildProfile};
+use crate::{
+    build_info::BuildInfo,
+    config::{BuildConfiguration, BuildProfile},
+    error::Error,
+};
 
 pub fn build(root: &Path, build_config: &BuildConfiguration) -> Result<(), anyhow::Error> {
     debug!("building");
@@ -43,16 +48,15 @@ pub fn build(root: &Path, build_config: &BuildConfiguration) -> Result<(), anyho
         ..Default::default()
     };
 
-    Build::try_from_opts(options).and_then(|mut b| b.run())?;
+    Build::try_from_opts(options)
+        .and_then(|mut b| b.run())
+        .context(Error::BuildFailed)?;
 
     debug!("finished executing wasm-pack build");
 
-    let target_dir = build_config
-        .path
-        .as_ref()
-        .map(|p| root.join(p))
-        .unwrap_or_else(|| root.into())
-        .join("pkg");
+    let target_dir = super::output_dir(root, build_config);
+
+    super::wasm_opt::optimize(&target_dir, &build_config.wasm_opt)?;
 
     let mut generated_js = None;
     for r in fs::read_dir(&target_dir)? {
@@ -68,14 +72,22 @@ pub fn build(root: &Path, build_config: &BuildConfiguration) -> Result<(), anyho
             generated_js = Some(entry.path());
         }
     }
-    let generated_js = generated_js
-        .ok_or_else(|| anyhow!("error: no js files found in {}", target_dir.display()))?;
+    let generated_js = generated_js.ok_or_else(|| Error::MissingFiles {
+        path: target_dir.clone(),
+        description: "no js files found in build output".to_owned(),
+    })?;
 
     debug!("processing js file");
 
     let generated_js_contents = fs::read_to_string(&generated_js)?;
 
-    let processed_js = process_js(&generated_js, &generated_js_contents, &out_name)?;
+    let build_info = BuildInfo::for_js(root, build_config);
+    let processed_js = process_js(
+        &generated_js,
+        &generated_js_contents,
+        &out_name,
+        build_info.as_ref(),
+    )?;
 
     debug!("writing processed js to {}", generated_js.display());
 
@@ -86,46 +98,195 @@ pub fn build(root: &Path, build_config: &BuildConfiguration) -> Result<(), anyho
     Ok(())
 }
 
-fn process_js(file_name: &Path, input: &str, out_name: &String) -> Result<String, anyhow::Error> {
-    // first, replace the TextEncoder/TextDecoder load step with a polyfill,
-    // as screeps' js environment doesn't give us access to `util`.
-    // also remove the filesystem load of the wasm bytes and replace with a simple
-    // require.
-    let bindgen_output_regex = regex::Regex::new(&format!(
-        "(?s)(.+)\n[^\n]+{}(.+){}[^\']+{}.+",
-        regex::escape(" = require(`util`);"),
-        regex::escape("const path = require('path').join(__dirname, '"),
-        regex::escape(".wasm');\nconst bytes = require('fs').readFileSync(path);"),
-    ))
-    .expect("expected pre-set regex to succeed");
-
-    let captures = bindgen_output_regex.captures(input).ok_or_else(|| {
-        anyhow!(
-            "'wasm-pack' generated unexpected JS output! This means it's updated without \
-             'cargo screeps' also having updated. Please report this issue to \
-             https://github.com/rustyscreeps/cargo-screeps/issues and include \
-             the first ~30 lines of {}",
-            file_name.display(),
-        )
-    })?;
+/// The ways wasm-bindgen's output loads the wasm module from the filesystem,
+/// each named, matching from the first line of the loading code. Everything
+/// from there to the end of the file is replaced by a loader which works in
+/// Screeps.
+static WASM_LOADERS: LazyLock<Vec<(&str, Regex)>> = LazyLock::new(|| {
+    [
+        // older wasm-bindgen joins the path with the `path` module
+        (
+            "path.join",
+            r"(?m)^const path = require\('path'\)\.join\(__dirname, '[^'\n]+\.wasm'\);\nconst bytes = require\('fs'\)\.readFileSync\(path\);$",
+        ),
+        // newer wasm-bindgen builds the path with a template string
+        (
+            "wasmPath",
+            r"(?m)^const wasmPath = `\$\{__dirname\}/[^`\n]+\.wasm`;\nconst wasmBytes = require\('fs'\)\.readFileSync\(wasmPath\);$",
+        ),
+    ]
+    .into_iter()
+    .map(|(name, pattern)| {
+        let regex = Regex::new(pattern).expect("expected pre-set regex to succeed");
+        (name, regex)
+    })
+    .collect()
+});
+
+/// The line loading `TextEncoder` and `TextDecoder` from node's `util` module,
+/// which Screeps doesn't have.
+static UTIL_REQUIRE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?m)^(?:const|let|var) \{[^}\n]*\} = require\((?:`util`|'util'|"util")\);\n"#)
+        .expect("expected pre-set regex to succeed")
+});
+
+/// The declaration of the imports object passed to the wasm module, which
+/// the polyfill goes after when `TextEncoder` and `TextDecoder` aren't loaded
+/// from `util`.
+static IMPORTS_DECLARATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^let imports = \{\};\n").expect("expected pre-set regex to succeed")
+});
+
+/// The declaration of `wasm` older wasm-bindgen puts before the functions
+/// which use it.
+static WASM_DECLARATION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^let wasm;$").expect("expected pre-set regex to succeed"));
+
+fn process_js(
+    file_name: &Path,
+    input: &str,
+    out_name: &String,
+    build_info: Option<&BuildInfo>,
+) -> Result<String, anyhow::Error> {
+    let mismatch = |missing: &str| {
+        debug!("couldn't find {} in {}", missing, file_name.display());
+        Error::BindgenOutputMismatch {
+            path: file_name.to_owned(),
+        }
+    };
+
+    // replace the filesystem load of the wasm bytes, and everything after it,
+    // with a simple require
+    let (loader, loader_start) = WASM_LOADERS
+        .iter()
+        .find_map(|(name, pattern)| Some((name, pattern.find(input)?.start())))
+        .ok_or_else(|| mismatch("the wasm module loader"))?;
+    debug!("found wasm-bindgen's {} wasm module loader", loader);
+    let (body, loader_code) = input.split_at(loader_start);
 
-    // CC-0 TextEncoder/TextDecoder polyfill from https://github.com/anonyco/FastestSmallestTextEncoderDecoder
-    Ok(format!(
+    // replace the TextEncoder/TextDecoder load step with a polyfill, as
+    // screeps' js environment doesn't give us access to `util`; output which
+    // expects them to be global gets the polyfill after the imports object
+    let (before_polyfill, after_polyfill) = match UTIL_REQUIRE.find(body) {
+        Some(found) => (&body[..found.start()], &body[found.end()..]),
+        None => {
+            let imports = IMPORTS_DECLARATION
+                .find(body)
+                .ok_or_else(|| mismatch("the util require or imports declaration"))?;
+            body.split_at(imports.end())
+        }
+    };
+
+    // newer wasm-bindgen declares `wasm` along with loading the module, rather
+    // than before the functions which use it
+    let declares_wasm = WASM_DECLARATION.is_match(body);
+    // and may export a start function to run once instantiated
+    let starts = loader_code.contains("wasm.__wbindgen_start();");
+
+    let mut output = format!(
         r#"{}
-'use strict';(function(r){{function x(){{}}function y(){{}}var z=String.fromCharCode,v={{}}.toString,A=v.call(r.SharedArrayBuffer),B=v(),q=r.Uint8Array,t=q||Array,w=q?ArrayBuffer:t,C=w.isView||function(g){{return g&&"length"in g}},D=v.call(w.prototype);w=y.prototype;var E=r.TextEncoder,a=new (q?Uint16Array:t)(32);x.prototype.decode=function(g){{if(!C(g)){{var l=v.call(g);if(l!==D&&l!==A&&l!==B)throw TypeError("Failed to execute 'decode' on 'TextDecoder': The provided value is not of type '(ArrayBuffer or ArrayBufferView)'");
-g=q?new t(g):g||[]}}for(var f=l="",b=0,c=g.length|0,u=c-32|0,e,d,h=0,p=0,m,k=0,n=-1;b<c;){{for(e=b<=u?32:c-b|0;k<e;b=b+1|0,k=k+1|0){{d=g[b]&255;switch(d>>4){{case 15:m=g[b=b+1|0]&255;if(2!==m>>6||247<d){{b=b-1|0;break}}h=(d&7)<<6|m&63;p=5;d=256;case 14:m=g[b=b+1|0]&255,h<<=6,h|=(d&15)<<6|m&63,p=2===m>>6?p+4|0:24,d=d+256&768;case 13:case 12:m=g[b=b+1|0]&255,h<<=6,h|=(d&31)<<6|m&63,p=p+7|0,b<c&&2===m>>6&&h>>p&&1114112>h?(d=h,h=h-65536|0,0<=h&&(n=(h>>10)+55296|0,d=(h&1023)+56320|0,31>k?(a[k]=n,k=k+1|0,n=-1):
-(m=n,n=d,d=m))):(d>>=8,b=b-d-1|0,d=65533),h=p=0,e=b<=u?32:c-b|0;default:a[k]=d;continue;case 11:case 10:case 9:case 8:}}a[k]=65533}}f+=z(a[0],a[1],a[2],a[3],a[4],a[5],a[6],a[7],a[8],a[9],a[10],a[11],a[12],a[13],a[14],a[15],a[16],a[17],a[18],a[19],a[20],a[21],a[22],a[23],a[24],a[25],a[26],a[27],a[28],a[29],a[30],a[31]);32>k&&(f=f.slice(0,k-32|0));if(b<c){{if(a[0]=n,k=~n>>>31,n=-1,f.length<l.length)continue}}else-1!==n&&(f+=z(n));l+=f;f=""}}return l}};w.encode=function(g){{g=void 0===g?"":""+g;var l=g.length|
-0,f=new t((l<<1)+8|0),b,c=0,u=!q;for(b=0;b<l;b=b+1|0,c=c+1|0){{var e=g.charCodeAt(b)|0;if(127>=e)f[c]=e;else{{if(2047>=e)f[c]=192|e>>6;else{{a:{{if(55296<=e)if(56319>=e){{var d=g.charCodeAt(b=b+1|0)|0;if(56320<=d&&57343>=d){{e=(e<<10)+d-56613888|0;if(65535<e){{f[c]=240|e>>18;f[c=c+1|0]=128|e>>12&63;f[c=c+1|0]=128|e>>6&63;f[c=c+1|0]=128|e&63;continue}}break a}}e=65533}}else 57343>=e&&(e=65533);!u&&b<<1<c&&b<<1<(c-7|0)&&(u=!0,d=new t(3*l),d.set(f),f=d)}}f[c]=224|e>>12;f[c=c+1|0]=128|e>>6&63}}f[c=c+1|0]=128|e&63}}}}return q?
-f.subarray(0,c):f.slice(0,c)}};E||(r.TextDecoder=x,r.TextEncoder=y)}})(""+void 0==typeof global?""+void 0==typeof self?this:self:global);
 {}
+
+{}{}
 const bytes = require('{}_bg');
 
 const wasmModule = new WebAssembly.Module(bytes);
 module.exports.initialize_instance = function() {{
     const wasmInstance = new WebAssembly.Instance(wasmModule, imports);
     wasm = wasmInstance.exports;
-    module.exports.__wasm = wasm;
+    module.exports.__wasm = wasm;{}
 }}"#,
-        &captures[1], &captures[2], out_name
-    ))
+        before_polyfill
+            .strip_suffix('\n')
+            .unwrap_or(before_polyfill),
+        super::TEXT_CODING_POLYFILL,
+        after_polyfill,
+        if declares_wasm { "" } else { "let wasm;\n" },
+        out_name,
+        if starts {
+            "\n    wasm.__wbindgen_start();"
+        } else {
+            ""
+        },
+    );
+
+    if let Some(build_info) = build_info {
+        output.push_str(&format!(
+            "\nmodule.exports.__build_info = {};\n",
+            serde_json::to_string(build_info)?
+        ));
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::BuildMode;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/bindgen")
+            .join(name)
+    }
+
+    /// Checks the processed output of a fixture against the `.expected.js`
+    /// file next to it.
+    fn check_golden(name: &str) {
+        let input_path = fixture(&format!("{name}.js"));
+        let input = fs::read_to_string(&input_path).unwrap();
+        let output = process_js(&input_path, &input, &"bot".to_owned(), None).unwrap();
+
+        let expected = fs::read_to_string(fixture(&format!("{name}.expected.js"))).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn processes_path_join_loader() {
+        check_golden("path-join");
+    }
+
+    #[test]
+    fn processes_wasm_path_loader() {
+        check_golden("wasm-path");
+    }
+
+    #[test]
+    fn appends_build_info() {
+        let input_path = fixture("wasm-path.js");
+        let input = fs::read_to_string(&input_path).unwrap();
+        let build_info = BuildInfo {
+            crate_name: Some("bot".to_owned()),
+            crate_version: Some("0.1.0".to_owned()),
+            cargo_screeps_version: "0.0.0".to_owned(),
+            git_commit: None,
+            git_dirty: None,
+            build_profile: BuildProfile::Release,
+            build_mode: BuildMode::World,
+            rustc_version: None,
+            timestamp: Some(0),
+        };
+        let output = process_js(&input_path, &input, &"bot".to_owned(), Some(&build_info)).unwrap();
+
+        assert!(output.ends_with(
+            "\nmodule.exports.__build_info = {\"crate_name\":\"bot\",\"crate_version\":\"0.1.0\",\
+             \"cargo_screeps_version\":\"0.0.0\",\"git_commit\":null,\"git_dirty\":null,\
+             \"build_profile\":\"release\",\"build_mode\":\"world\",\"rustc_version\":null,\
+             \"timestamp\":0};\n"
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_loader() {
+        let input = "let imports = {};\nconst bytes = fetch('bot_bg.wasm');\n";
+        let error = process_js(Path::new("bot.js"), input, &"bot".to_owned(), None).unwrap_err();
+
+        assert!(matches!(
+            Error::find(&error),
+            Some(Error::BindgenOutputMismatch { .. })
+        ));
+    }
 }
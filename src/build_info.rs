@@ -0,0 +1,204 @@
+use std::{
+    env, fs,
+    path::Path,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{BuildConfiguration, BuildMode, BuildProfile};
+
+/// What went into a build: the crate and commit it was built from, and the
+/// options and toolchain it was built with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildInfo {
+    pub crate_name: Option<String>,
+    pub crate_version: Option<String>,
+    pub cargo_screeps_version: String,
+    /// The commit checked out when building, if the crate is in a git
+    /// repository.
+    pub git_commit: Option<String>,
+    /// Whether there were uncommitted changes to tracked files when building.
+    pub git_dirty: Option<bool>,
+    pub build_profile: BuildProfile,
+    pub build_mode: BuildMode,
+    pub rustc_version: Option<String>,
+    /// When the build ran, in seconds since the unix epoch; only recorded in
+    /// the generated JS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+impl BuildInfo {
+    /// The information to add to the generated JS for a build, if enabled,
+    /// including when the build ran.
+    pub fn for_js(root: &Path, build_config: &BuildConfiguration) -> Option<Self> {
+        let config = &build_config.build_info;
+        if !config.enabled.unwrap_or(false) {
+            return None;
+        }
+
+        // SOURCE_DATE_EPOCH is the usual way to fix timestamps for reproducible
+        // builds
+        let timestamp = config.timestamp.or_else(|| {
+            match env::var("SOURCE_DATE_EPOCH").map(|epoch| epoch.trim().parse()) {
+                Ok(Ok(epoch)) => Some(epoch),
+                Ok(Err(_)) => {
+                    warn!("ignoring SOURCE_DATE_EPOCH, which isn't a number of seconds");
+                    None
+                }
+                Err(_) => None,
+            }
+            .or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|now| now.as_secs())
+            })
+        });
+
+        Some(BuildInfo {
+            timestamp,
+            ..BuildInfo::gather(root, build_config)
+        })
+    }
+
+    /// Gathers information about a build of the crate at `root` with
+    /// `build_config`, leaving out anything which can't be found, and using
+    /// the values set in its `build_info` section instead of those found.
+    pub fn gather(root: &Path, build_config: &BuildConfiguration) -> Self {
+        let overrides = &build_config.build_info;
+        let crate_dir = match &build_config.path {
+            Some(path) => root.join(path),
+            None => root.to_owned(),
+        };
+        let (crate_name, crate_version) = read_package(&crate_dir);
+        let crate_version = overrides.crate_version.clone().or(crate_version);
+
+        let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+
+        BuildInfo {
+            crate_name,
+            crate_version,
+            cargo_screeps_version: overrides
+                .cargo_screeps_version
+                .clone()
+                .unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_owned()),
+            git_commit: overrides.git_commit.clone().or_else(|| {
+                command_output(
+                    process::Command::new("git")
+                        .arg("-C")
+                        .arg(&crate_dir)
+                        .args(["rev-parse", "HEAD"]),
+                )
+            }),
+            git_dirty: overrides.git_dirty.or_else(|| {
+                command_output(
+                    process::Command::new("git")
+                        .arg("-C")
+                        .arg(&crate_dir)
+                        // build output written before this is gathered is
+                        // usually untracked, and shouldn't count
+                        .args(["status", "--porcelain", "--untracked-files=no"]),
+                )
+                .map(|status| !status.is_empty())
+            }),
+            build_profile: build_config
+                .build_profile
+                .clone()
+                .unwrap_or(BuildProfile::Release),
+            build_mode: build_config.build_mode.clone().unwrap_or(BuildMode::World),
+            rustc_version: overrides
+                .rustc_version
+                .clone()
+                .or_else(|| command_output(process::Command::new(rustc).arg("--version"))),
+            timestamp: None,
+        }
+    }
+}
+
+/// Reads the crate's name and version from its `Cargo.toml`, if they're set
+/// there rather than inherited from a workspace.
+fn read_package(crate_dir: &Path) -> (Option<String>, Option<String>) {
+    let manifest_path = crate_dir.join("Cargo.toml");
+    let manifest: toml::Table = match fs::read_to_string(&manifest_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(toml::from_str(&contents)?))
+    {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            warn!("couldn't read {}: {}", manifest_path.display(), e);
+            return (None, None);
+        }
+    };
+
+    let package = manifest.get("package");
+    let field = |name| {
+        package
+            .and_then(|package| package.get(name))
+            .and_then(toml::Value::as_str)
+            .map(ToOwned::to_owned)
+    };
+
+    (field("name"), field("version"))
+}
+
+/// Runs a command, returning its trimmed output if it succeeds.
+fn command_output(command: &mut process::Command) -> Option<String> {
+    debug!("running {:?}", command);
+    match command.output() {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        }
+        Ok(output) => {
+            debug!(
+                "{:?} failed: {}",
+                command,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            debug!("couldn't run {:?}: {}", command, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BuildInfoConfiguration;
+
+    #[test]
+    fn uses_overrides_instead_of_found_values() {
+        let build_config = BuildConfiguration {
+            build_info: BuildInfoConfiguration {
+                git_commit: Some("0123456789abcdef0123456789abcdef01234567".to_owned()),
+                git_dirty: Some(false),
+                crate_version: Some("1.0.0".to_owned()),
+                rustc_version: Some("rustc 1.80.0 (051478957 2024-07-21)".to_owned()),
+                cargo_screeps_version: Some("0.5.0".to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let info = BuildInfo::gather(Path::new(env!("CARGO_MANIFEST_DIR")), &build_config);
+
+        assert_eq!(info.crate_name.as_deref(), Some("cargo-screeps"));
+        assert_eq!(info.crate_version.as_deref(), Some("1.0.0"));
+        assert_eq!(info.cargo_screeps_version, "0.5.0");
+        assert_eq!(
+            info.git_commit.as_deref(),
+            Some("0123456789abcdef0123456789abcdef01234567")
+        );
+        assert_eq!(info.git_dirty, Some(false));
+        assert_eq!(
+            info.rustc_version.as_deref(),
+            Some("rustc 1.80.0 (051478957 2024-07-21)")
+        );
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use base64::{
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    build_info::BuildInfo,
+    config::ModeConfiguration,
+    deploy_cache,
+    progress::{self, Event},
+    size, upload,
+};
+
+/// The version of the bundle format written by this version of cargo-screeps.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The name of the file holding the branch and manifest in `.tar.gz` bundles,
+/// alongside a file for each module.
+pub const BUNDLE_FILE: &str = "bundle.json";
+
+// uploads are sent unpadded, but the server or other tools may pad them
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// The modules a mode would deploy, as sent to the server when uploading, with
+/// a manifest recording how they were built.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Bundle {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub modules: BTreeMap<String, serde_json::Value>,
+    pub branch: String,
+    pub manifest: Manifest,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// The mode the bundle was packaged with.
+    pub mode: String,
+    #[serde(flatten)]
+    pub build_info: BuildInfo,
+    /// The SHA-256 hash of each module's file contents, by module name.
+    pub module_hashes: BTreeMap<String, String>,
+    /// The hash of all modules together, as recorded in the deploy cache.
+    pub modules_hash: String,
+}
+
+impl Bundle {
+    /// Packages the modules a mode would deploy from the last build.
+    pub fn package(
+        root: &Path,
+        mode_name: &str,
+        mode: &ModeConfiguration,
+    ) -> Result<Self, anyhow::Error> {
+        let modules = upload::collect_modules(root, &mode.build().path, mode.include_files())?;
+        // warn about oversize code now, rather than when it's deployed
+        size::check_limit(&modules, true)?;
+
+        let mut module_hashes = BTreeMap::new();
+        for (name, contents) in &modules {
+            let (_, data) = module_file(name, contents)?;
+            module_hashes.insert(name.clone(), format!("{:x}", Sha256::digest(data)));
+        }
+
+        let branch = match mode {
+            ModeConfiguration::Copy(copy) => copy.branch.clone(),
+            ModeConfiguration::Upload(upload) => upload.branch.clone(),
+        };
+
+        Ok(Bundle {
+            manifest: Manifest {
+                format_version: FORMAT_VERSION,
+                mode: mode_name.to_owned(),
+                build_info: BuildInfo::gather(root, mode.build()),
+                module_hashes,
+                modules_hash: deploy_cache::hash_modules(&modules),
+            },
+            modules: modules.into_iter().collect(),
+            branch,
+        })
+    }
+
+    /// Reads a bundle from a `.json` file, or from a directory unpacked from a
+    /// `.tar.gz` bundle, checking its modules against the manifest.
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let bundle = if path.is_dir() {
+            read_dir(path)
+        } else {
+            fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| Ok(serde_json::from_str(&contents)?))
+        }
+        .with_context(|| format!("reading bundle {}", path.display()))?;
+
+        bundle
+            .verify()
+            .with_context(|| format!("checking bundle {}", path.display()))?;
+
+        Ok(bundle)
+    }
+
+    /// Writes the bundle as a `.tar.gz` archive if `path` ends with `.tar.gz`
+    /// or `.tgz`, or as JSON otherwise.
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if is_archive(path) {
+            self.write_archive(path)
+        } else {
+            fs::write(path, serde_json::to_string_pretty(self)?).map_err(Into::into)
+        }
+        .with_context(|| format!("writing bundle {}", path.display()))?;
+
+        progress::emit(Event::BundleWritten {
+            path: path.to_owned(),
+            modules_hash: self.manifest.modules_hash.clone(),
+        });
+
+        Ok(())
+    }
+
+    /// The modules, the way they're sent to the server.
+    pub fn modules(&self) -> HashMap<String, serde_json::Value> {
+        self.modules.clone().into_iter().collect()
+    }
+
+    fn write_archive(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut archive = tar::Builder::new(GzEncoder::new(
+            fs::File::create(path)?,
+            Compression::default(),
+        ));
+
+        let header = Bundle {
+            modules: BTreeMap::new(),
+            branch: self.branch.clone(),
+            manifest: self.manifest.clone(),
+        };
+        append_file(
+            &mut archive,
+            BUNDLE_FILE,
+            serde_json::to_string_pretty(&header)?.as_bytes(),
+        )?;
+
+        for (name, contents) in &self.modules {
+            let (file_name, data) = module_file(name, contents)?;
+            append_file(&mut archive, &file_name, &data)?;
+        }
+
+        archive.into_inner()?.finish()?;
+
+        Ok(())
+    }
+
+    fn verify(&self) -> Result<(), anyhow::Error> {
+        ensure!(
+            self.manifest.format_version <= FORMAT_VERSION,
+            "bundle format version {} is newer than this version of cargo-screeps supports ({}); \
+             update cargo-screeps to deploy it",
+            self.manifest.format_version,
+            FORMAT_VERSION
+        );
+
+        for (name, contents) in &self.modules {
+            let (_, data) = module_file(name, contents)?;
+            let hash = format!("{:x}", Sha256::digest(data));
+            match self.manifest.module_hashes.get(name) {
+                Some(expected) if *expected == hash => {}
+                Some(_) => bail!("module '{}' doesn't match its hash in the manifest", name),
+                None => bail!("module '{}' isn't listed in the manifest", name),
+            }
+        }
+        for name in self.manifest.module_hashes.keys() {
+            ensure!(
+                self.modules.contains_key(name),
+                "module '{}' listed in the manifest is missing",
+                name
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a bundle or build output at `path` is a `.tar.gz` archive.
+pub fn is_archive(path: &Path) -> bool {
+    let file_name = path.to_string_lossy();
+    file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz")
+}
+
+/// Unpacks a `.tar.gz` archive into `out_dir`, replacing anything unpacked
+/// there before. Archives of a single directory are unpacked to the files in
+/// it.
+pub fn unpack(archive: &Path, out_dir: &Path) -> Result<PathBuf, anyhow::Error> {
+    if out_dir.exists() {
+        fs::remove_dir_all(out_dir)?;
+    }
+    fs::create_dir_all(out_dir)?;
+
+    tar::Archive::new(GzDecoder::new(fs::File::open(archive)?))
+        .unpack(out_dir)
+        .with_context(|| format!("unpacking {}", archive.display()))?;
+
+    let entries = fs::read_dir(out_dir)?.collect::<Result<Vec<_>, _>>()?;
+    match &entries[..] {
+        [entry] if entry.path().is_dir() => Ok(entry.path()),
+        _ => Ok(out_dir.to_owned()),
+    }
+}
+
+/// The file a module is written to, named after the module, and its contents.
+pub fn module_file(
+    name: &str,
+    contents: &serde_json::Value,
+) -> Result<(String, Vec<u8>), anyhow::Error> {
+    // module names can come from the server or a bundle; don't let them escape
+    // the directory they're written to
+    ensure!(
+        !name.is_empty() && !name.contains(['/', '\\']) && name != "." && name != "..",
+        "invalid module name '{}'",
+        name
+    );
+
+    match contents {
+        serde_json::Value::String(source) => Ok((format!("{name}.js"), source.as_bytes().to_vec())),
+        serde_json::Value::Object(object) => match object.get("binary") {
+            Some(serde_json::Value::String(binary)) => Ok((
+                format!("{name}.wasm"),
+                BASE64
+                    .decode(binary)
+                    .with_context(|| format!("decoding binary module '{name}'"))?,
+            )),
+            _ => bail!("binary module '{}' has no binary data", name),
+        },
+        _ => bail!("module '{}' has unexpected contents", name),
+    }
+}
+
+/// Reads a bundle unpacked from a `.tar.gz` archive.
+fn read_dir(dir: &Path) -> Result<Bundle, anyhow::Error> {
+    let mut bundle: Bundle = serde_json::from_str(&fs::read_to_string(dir.join(BUNDLE_FILE))?)?;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name() == Some(BUNDLE_FILE.as_ref()) {
+            continue;
+        }
+
+        let name = path
+            .file_stem()
+            .ok_or_else(|| anyhow!("unexpected file {} in bundle", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let contents = match path.extension().and_then(|e| e.to_str()) {
+            Some("js") => serde_json::Value::String(fs::read_to_string(&path)?),
+            Some("wasm") => serde_json::json!({
+                "binary": base64::engine::general_purpose::STANDARD_NO_PAD.encode(fs::read(&path)?)
+            }),
+            _ => bail!("unexpected file {} in bundle", path.display()),
+        };
+        bundle.modules.insert(name, contents);
+    }
+
+    Ok(bundle)
+}
+
+/// Adds a file to an archive with fixed metadata, so packaging the same modules
+/// twice gives the same archive.
+fn append_file<W: io::Write>(
+    archive: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> Result<(), anyhow::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    archive.append_data(&mut header, path, data)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WASM: &[u8] = b"\0asm\x01\0\0\0\xff\xfe";
+
+    /// Creates a project with built modules in a fresh directory.
+    fn project(test: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "cargo-screeps-bundle-{}-{}",
+            test,
+            std::process::id()
+        ));
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        fs::create_dir_all(root.join("pkg")).unwrap();
+        fs::write(
+            root.join("pkg/bot.js"),
+            "module.exports.loop = function() {};\n",
+        )
+        .unwrap();
+        fs::write(root.join("pkg/bot_bg.wasm"), WASM).unwrap();
+        root
+    }
+
+    fn package(root: &Path) -> Bundle {
+        let mode: ModeConfiguration = toml::from_str(
+            r#"
+            type = "copy"
+            destination = "out"
+            branch = "main"
+            include_files = ["pkg"]
+            "#,
+        )
+        .unwrap();
+        Bundle::package(root, "local", &mode).unwrap()
+    }
+
+    fn assert_same(read: &Bundle, written: &Bundle) {
+        assert_eq!(read.modules, written.modules);
+        assert_eq!(read.branch, "main");
+        assert_eq!(read.manifest.mode, "local");
+        assert_eq!(read.manifest.module_hashes, written.manifest.module_hashes);
+        assert_eq!(read.manifest.modules_hash, written.manifest.modules_hash);
+    }
+
+    #[test]
+    fn round_trips_json_bundles() {
+        let root = project("round-trips-json");
+        let bundle = package(&root);
+        let path = root.join("local.json");
+
+        bundle.write(&path).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["modules"]["bot"], bundle.modules["bot"]);
+
+        let read = Bundle::read(&path).unwrap();
+        assert_same(&read, &bundle);
+        assert_eq!(
+            module_file("bot_bg", &read.modules["bot_bg"]).unwrap().1,
+            WASM
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn round_trips_archive_bundles() {
+        let root = project("round-trips-archive");
+        let bundle = package(&root);
+        let path = root.join("local.tar.gz");
+
+        bundle.write(&path).unwrap();
+        let first = fs::read(&path).unwrap();
+        package(&root).write(&path).unwrap();
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            first,
+            "archives should be reproducible"
+        );
+
+        // the archive holds each module as a file, rather than inside the JSON
+        let dir = unpack(&path, &root.join("unpacked")).unwrap();
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["bot.js", "bot_bg.wasm", BUNDLE_FILE]);
+        assert_eq!(fs::read(dir.join("bot_bg.wasm")).unwrap(), WASM);
+        let header: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join(BUNDLE_FILE)).unwrap()).unwrap();
+        assert!(header.get("modules").is_none());
+
+        assert_same(&Bundle::read(&dir).unwrap(), &bundle);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rejects_tampered_modules() {
+        let root = project("rejects-tampered");
+        let bundle = package(&root);
+
+        let path = root.join("local.json");
+        bundle.write(&path).unwrap();
+        let mut written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        written["modules"]["bot"] = "module.exports.loop = function() { evil(); };".into();
+        fs::write(&path, written.to_string()).unwrap();
+
+        let error = format!("{:#}", Bundle::read(&path).unwrap_err());
+        assert!(
+            error.contains("module 'bot' doesn't match its hash in the manifest"),
+            "{error}"
+        );
+
+        let path = root.join("local.tar.gz");
+        bundle.write(&path).unwrap();
+        let dir = unpack(&path, &root.join("unpacked")).unwrap();
+        fs::write(dir.join("bot_bg.wasm"), b"\0asm\x01\0\0\0").unwrap();
+
+        let error = format!("{:#}", Bundle::read(&dir).unwrap_err());
+        assert!(
+            error.contains("module 'bot_bg' doesn't match its hash in the manifest"),
+            "{error}"
+        );
+
+        fs::remove_file(dir.join("bot.js")).unwrap();
+        fs::write(dir.join("bot_bg.wasm"), WASM).unwrap();
+        let error = format!("{:#}", Bundle::read(&dir).unwrap_err());
+        assert!(
+            error.contains("module 'bot' listed in the manifest is missing"),
+            "{error}"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
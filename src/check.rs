@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use log::*;
+use merge::Merge;
+
+use crate::{
+    config::{Authentication, Configuration, ModeConfiguration},
+    error::Error,
+    progress,
+    setup::MessageFormat,
+};
+
+/// The placeholder token in the default configuration.
+const PLACEHOLDER_TOKEN: &str = "your auth token";
+
+/// Checks that the configuration can be read, and that each mode's paths exist
+/// and its credentials are set, reporting every problem found.
+///
+/// With `--message-format json`, also prints the problems as a `config-checked`
+/// event.
+pub fn check(
+    root: &Path,
+    config_path: &Path,
+    strict: bool,
+    message_format: MessageFormat,
+) -> Result<(), anyhow::Error> {
+    let config = Configuration::read(config_path, strict)?;
+    let mut problems = Vec::new();
+
+    if let Some(mode) = &config.default_deploy_mode {
+        if !config.modes.contains_key(mode) {
+            problems.push(format!("default_deploy_mode {mode} isn't defined"));
+        }
+    }
+    let mut groups: Vec<_> = config.groups.keys().collect();
+    groups.sort();
+    for group in groups {
+        // the same expansion deploying the group uses, through nested groups
+        for member in config.expand_groups(std::slice::from_ref(group))? {
+            if !config.modes.contains_key(&member) {
+                problems.push(format!(
+                    "group {group} includes {member}, which isn't a mode"
+                ));
+            }
+        }
+    }
+
+    for problem in &problems {
+        error!("{}", problem);
+    }
+
+    let mut modes: Vec<_> = config.modes.iter().collect();
+    modes.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, mode) in modes {
+        let mode_problems = check_mode(root, &config, mode);
+        if mode_problems.is_empty() {
+            info!("mode {}: ok", name);
+        } else {
+            for problem in &mode_problems {
+                error!("mode {}: {}", name, problem);
+            }
+        }
+        problems.extend(mode_problems);
+    }
+
+    if message_format == MessageFormat::Json {
+        progress::print_json(&serde_json::json!({
+            "reason": "config-checked",
+            "path": config_path,
+            "problems": problems,
+        }));
+    }
+
+    match problems.len() {
+        0 => {
+            info!("configuration at {} is valid", config_path.display());
+            Ok(())
+        }
+        n => Err(match n {
+            1 => anyhow!("found 1 problem"),
+            n => anyhow!("found {} problems", n),
+        })
+        .context(Error::InvalidConfig {
+            path: config_path.to_owned(),
+        }),
+    }
+}
+
+fn check_mode(root: &Path, config: &Configuration, mode: &ModeConfiguration) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut build = mode.build().clone();
+    build.merge(config.build.clone());
+    let base = match &build.path {
+        Some(path) => {
+            let path = root.join(path);
+            if !path.is_dir() {
+                problems.push(format!("build path {} doesn't exist", path.display()));
+            }
+            path
+        }
+        None => root.to_owned(),
+    };
+
+    for include in mode.include_files() {
+        // the build creates pkg, so it's fine for it not to exist yet
+        if include == Path::new("pkg") {
+            continue;
+        }
+        let path = base.join(include);
+        if !path.is_dir() {
+            problems.push(format!(
+                "include_files directory {} doesn't exist",
+                path.display()
+            ));
+        }
+    }
+
+    if let ModeConfiguration::Upload(upload) = mode {
+        match upload.authentication.resolve() {
+            Ok(Authentication::Token { auth_token }) => {
+                if auth_token.trim().is_empty() {
+                    problems.push("auth_token is empty".to_owned());
+                } else if auth_token == PLACEHOLDER_TOKEN {
+                    problems.push(
+                        "auth_token is still the placeholder from the default configuration"
+                            .to_owned(),
+                    );
+                }
+            }
+            Ok(Authentication::Basic { username, password }) => {
+                if username.trim().is_empty() {
+                    problems.push("username is empty".to_owned());
+                }
+                if password.is_empty() {
+                    problems.push("password is empty".to_owned());
+                }
+            }
+            Err(e) => problems.push(format!("{e:#}")),
+        }
+    }
+
+    problems
+}
@@ -1,15 +1,17 @@
 use std::{
     collections::{BTreeSet, HashMap},
-    fs,
+    env, fmt, fs,
     path::{Path, PathBuf},
 };
 
-use anyhow::{ensure, Context};
+use anyhow::{anyhow, bail, ensure, Context};
 use log::*;
 use merge::Merge;
-use serde::Deserialize;
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Clone, Debug, Deserialize)]
+use crate::{error::Error, redact};
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BuildProfile {
     Dev,
@@ -17,65 +19,302 @@ pub enum BuildProfile {
     Release,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BuildMode {
     World,
     Arena,
 }
 
-#[derive(Clone, Debug, Deserialize, Default, Merge)]
+/// Optimization levels passed to `wasm-opt`, named after its flags.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WasmOptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    O4,
+    Os,
+    Oz,
+}
+
+impl WasmOptLevel {
+    pub fn flag(&self) -> &'static str {
+        match self {
+            WasmOptLevel::O0 => "-O0",
+            WasmOptLevel::O1 => "-O1",
+            WasmOptLevel::O2 => "-O2",
+            WasmOptLevel::O3 => "-O3",
+            WasmOptLevel::O4 => "-O4",
+            WasmOptLevel::Os => "-Os",
+            WasmOptLevel::Oz => "-Oz",
+        }
+    }
+}
+
+/// Settings for running binaryen's `wasm-opt` on the built module, after
+/// wasm-pack has finished.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default, Merge)]
+pub struct WasmOptConfiguration {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<WasmOptLevel>,
+    /// WebAssembly features to enable, such as `bulk-memory`.
+    #[merge(strategy = merge::vec::prepend)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// Additional passes to run, such as `strip-producers`.
+    #[merge(strategy = merge::vec::prepend)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub passes: Vec<String>,
+    /// The `wasm-opt` binary to run, instead of the one found on `PATH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<PathBuf>,
+    /// Warn and leave the module unoptimized when `wasm-opt` can't be found,
+    /// rather than failing the build.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip_if_missing: Option<bool>,
+}
+
+/// Settings for adding information about the build to the generated JS as
+/// `__build_info`, with values to use in place of the ones found when
+/// building, for reproducible builds.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default, Merge)]
+pub struct BuildInfoConfiguration {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_dirty: Option<bool>,
+    /// The build time, in seconds since the unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crate_version: Option<String>,
+    /// The `rustc --version` output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rustc_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cargo_screeps_version: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default, Merge)]
 pub struct BuildConfiguration {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub build_profile: Option<BuildProfile>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub build_mode: Option<BuildMode>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub out_name: Option<String>,
     #[merge(strategy = merge::vec::prepend)]
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub extra_options: Vec<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_name_section: Option<bool>,
+    /// Fail the build if the built modules' encoded size exceeds this many bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_budget: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub wasm_opt: WasmOptConfiguration,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub build_info: BuildInfoConfiguration,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged, rename_all = "lowercase")]
+/// A deploy mode, which either copies the built files to a directory or uploads
+/// them to a server.
+///
+/// The kind of mode is chosen by its `type` key, or for configurations without
+/// one, guessed from whether it has a `destination`.
+#[derive(Clone, Debug)]
 pub enum ModeConfiguration {
-    Copy {
-        destination: PathBuf,
-        #[serde(default = "default_branch")]
-        branch: String,
-        #[serde(default)]
-        build: BuildConfiguration,
-        #[serde(default = "default_include_files")]
-        include_files: Vec<PathBuf>,
-        #[serde(default = "default_prune")]
-        prune: bool,
-    },
-    Upload {
-        #[serde(flatten)]
-        authentication: Authentication,
-        #[serde(default = "default_branch")]
-        branch: String,
-        #[serde(default)]
-        build: BuildConfiguration,
-        #[serde(default = "default_hostname")]
-        hostname: String,
-        #[serde(default = "default_include_files")]
-        include_files: Vec<PathBuf>,
-        #[serde(default = "default_ssl")]
-        ssl: bool,
-        #[serde(default = "default_port")]
-        port: u16,
-        #[serde(default)]
-        prefix: Option<String>,
-        #[serde(default)]
-        http_timeout: Option<u32>,
-    },
-}
-
-fn default_include_files() -> Vec<PathBuf> {
+    Copy(CopyConfiguration),
+    Upload(UploadConfiguration),
+}
+
+impl Serialize for ModeConfiguration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Tagged<'a, T> {
+            #[serde(rename = "type")]
+            mode_type: &'static str,
+            #[serde(flatten)]
+            mode: &'a T,
+        }
+
+        match self {
+            ModeConfiguration::Copy(copy) => Tagged {
+                mode_type: "copy",
+                mode: copy,
+            }
+            .serialize(serializer),
+            ModeConfiguration::Upload(upload) => Tagged {
+                mode_type: "upload",
+                mode: upload,
+            }
+            .serialize(serializer),
+        }
+    }
+}
+
+/// The values `type` can take in a mode.
+const MODE_TYPES: &[&str] = &["copy", "upload"];
+
+impl<'de> Deserialize<'de> for ModeConfiguration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut mode = toml::Table::deserialize(deserializer)?;
+        let mode_type = match mode.remove("type") {
+            Some(toml::Value::String(mode_type)) => Some(mode_type),
+            Some(other) => {
+                return Err(de::Error::invalid_type(
+                    de::Unexpected::Other(other.type_str()),
+                    &"\"copy\" or \"upload\"",
+                ))
+            }
+            None => None,
+        };
+        let mode = toml::Value::Table(mode);
+
+        match mode_type.as_deref() {
+            Some("copy") => mode
+                .try_into()
+                .map(ModeConfiguration::Copy)
+                .map_err(de::Error::custom),
+            Some("upload") => mode
+                .try_into()
+                .map(ModeConfiguration::Upload)
+                .map_err(de::Error::custom),
+            Some(other) => Err(de::Error::unknown_variant(other, MODE_TYPES)),
+            None => {
+                if let Ok(copy) = mode.clone().try_into() {
+                    return Ok(ModeConfiguration::Copy(copy));
+                }
+                mode.try_into().map(ModeConfiguration::Upload).map_err(|_| {
+                    de::Error::custom(
+                        "mode is neither a copy nor an upload mode; set `type` to \"copy\" or \
+                         \"upload\" for a precise error",
+                    )
+                })
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CopyConfiguration {
+    pub destination: PathBuf,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub build: BuildConfiguration,
+    #[serde(default = "default_include_files")]
+    pub include_files: Vec<PathBuf>,
+    #[serde(default = "default_prune")]
+    pub prune: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UploadConfiguration {
+    #[serde(flatten)]
+    pub authentication: AuthenticationConfiguration,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub build: BuildConfiguration,
+    #[serde(default = "default_include_files")]
+    pub include_files: Vec<PathBuf>,
+    #[serde(flatten)]
+    pub server: ServerConfiguration,
+}
+
+impl ModeConfiguration {
+    pub fn build(&self) -> &BuildConfiguration {
+        match self {
+            ModeConfiguration::Copy(copy) => &copy.build,
+            ModeConfiguration::Upload(upload) => &upload.build,
+        }
+    }
+
+    pub fn build_mut(&mut self) -> &mut BuildConfiguration {
+        match self {
+            ModeConfiguration::Copy(copy) => &mut copy.build,
+            ModeConfiguration::Upload(upload) => &mut upload.build,
+        }
+    }
+
+    pub fn include_files(&self) -> &Vec<PathBuf> {
+        match self {
+            ModeConfiguration::Copy(copy) => &copy.include_files,
+            ModeConfiguration::Upload(upload) => &upload.include_files,
+        }
+    }
+
+    pub fn include_files_mut(&mut self) -> &mut Vec<PathBuf> {
+        match self {
+            ModeConfiguration::Copy(copy) => &mut copy.include_files,
+            ModeConfiguration::Upload(upload) => &mut upload.include_files,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServerConfiguration {
+    #[serde(default = "default_hostname")]
+    pub hostname: String,
+    #[serde(default = "default_ssl")]
+    pub ssl: bool,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_timeout: Option<u32>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard: Option<String>,
+}
+
+impl ServerConfiguration {
+    /// Builds the URL of an API endpoint on this server, such as `user/code`.
+    pub fn api_url(&self, endpoint: &str) -> String {
+        format!(
+            "{}://{}:{}/{}",
+            if self.ssl { "https" } else { "http" },
+            self.hostname,
+            self.port,
+            match &self.prefix {
+                Some(prefix) => format!("{prefix}/api/{endpoint}"),
+                None => format!("api/{endpoint}"),
+            }
+        )
+    }
+
+    /// Builds the URL of this server's websocket endpoint.
+    pub fn socket_url(&self) -> String {
+        format!(
+            "{}://{}:{}/{}",
+            if self.ssl { "wss" } else { "ws" },
+            self.hostname,
+            self.port,
+            match &self.prefix {
+                Some(prefix) => format!("{prefix}/socket/websocket"),
+                None => "socket/websocket".to_string(),
+            }
+        )
+    }
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+pub fn default_include_files() -> Vec<PathBuf> {
     vec!["pkg".into(), "javascript".into()]
 }
 
@@ -99,25 +338,196 @@ fn default_port() -> u16 {
     443
 }
 
-#[derive(Clone, Debug, Deserialize)]
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff() -> u32 {
+    1
+}
+
+/// Credentials as written in the configuration, which may refer to environment
+/// variables; see [`AuthenticationConfiguration::resolve`].
+#[derive(Clone, Deserialize)]
 #[serde(untagged)]
+pub enum AuthenticationConfiguration {
+    Token { auth_token: String },
+    TokenEnv { auth_token_env: String },
+    Basic { username: String, password: String },
+}
+
+impl AuthenticationConfiguration {
+    /// Reads the credentials, filling in `${VAR}` references and
+    /// `auth_token_env` from the environment.
+    pub fn resolve(&self) -> Result<Authentication, anyhow::Error> {
+        let authentication = match self {
+            AuthenticationConfiguration::Token { auth_token } => Authentication::Token {
+                auth_token: interpolate_env(auth_token, "auth_token")?,
+            },
+            AuthenticationConfiguration::TokenEnv { auth_token_env } => Authentication::Token {
+                auth_token: env_var(auth_token_env, "auth_token_env")?,
+            },
+            AuthenticationConfiguration::Basic { username, password } => Authentication::Basic {
+                username: interpolate_env(username, "username")?,
+                password: interpolate_env(password, "password")?,
+            },
+        };
+
+        match &authentication {
+            Authentication::Token { auth_token } => redact::register_secret(auth_token),
+            Authentication::Basic { password, .. } => redact::register_secret(password),
+        }
+
+        Ok(authentication)
+    }
+}
+
+impl fmt::Debug for AuthenticationConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthenticationConfiguration::Token { .. } => f
+                .debug_struct("Token")
+                .field("auth_token", &REDACTED)
+                .finish(),
+            AuthenticationConfiguration::TokenEnv { auth_token_env } => f
+                .debug_struct("TokenEnv")
+                .field("auth_token_env", auth_token_env)
+                .finish(),
+            AuthenticationConfiguration::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &REDACTED)
+                .finish(),
+        }
+    }
+}
+
+/// Writes the credentials with secrets redacted, as in debug output.
+impl Serialize for AuthenticationConfiguration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            AuthenticationConfiguration::Token { .. } => {
+                let mut state = serializer.serialize_struct("Token", 1)?;
+                state.serialize_field("auth_token", REDACTED_VALUE)?;
+                state.end()
+            }
+            AuthenticationConfiguration::TokenEnv { auth_token_env } => {
+                let mut state = serializer.serialize_struct("TokenEnv", 1)?;
+                state.serialize_field("auth_token_env", auth_token_env)?;
+                state.end()
+            }
+            AuthenticationConfiguration::Basic { username, .. } => {
+                let mut state = serializer.serialize_struct("Basic", 2)?;
+                state.serialize_field("username", username)?;
+                state.serialize_field("password", REDACTED_VALUE)?;
+                state.end()
+            }
+        }
+    }
+}
+
+/// Credentials to send to the server.
+#[derive(Clone)]
 pub enum Authentication {
     Token { auth_token: String },
     Basic { username: String, password: String },
 }
 
+impl fmt::Debug for Authentication {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Authentication::Token { .. } => f
+                .debug_struct("Token")
+                .field("auth_token", &REDACTED)
+                .finish(),
+            Authentication::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &REDACTED)
+                .finish(),
+        }
+    }
+}
+
+/// Stands in for secrets in debug and serialized output.
+const REDACTED_VALUE: &str = "<redacted>";
+
+const REDACTED: Redacted = Redacted;
+
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED_VALUE)
+    }
+}
+
+/// Replaces each `${VAR}` in a configuration value with the value of the
+/// environment variable; `$${VAR}` is left as a literal `${VAR}`.
+fn interpolate_env(value: &str, field: &str) -> Result<String, anyhow::Error> {
+    let reference = regex::Regex::new(r"\$(\$?)\{([A-Za-z_][A-Za-z0-9_]*)\}")
+        .expect("expected pre-set regex to succeed");
+
+    let mut interpolated = String::new();
+    let mut last_end = 0;
+    for captures in reference.captures_iter(value) {
+        let whole = captures.get(0).expect("expected capture group 0 to exist");
+        interpolated.push_str(&value[last_end..whole.start()]);
+        if captures[1].is_empty() {
+            interpolated.push_str(&env_var(&captures[2], field)?);
+        } else {
+            interpolated.push_str(&whole.as_str()[1..]);
+        }
+        last_end = whole.end();
+    }
+    interpolated.push_str(&value[last_end..]);
+
+    Ok(interpolated)
+}
+
+fn env_var(name: &str, field: &str) -> Result<String, anyhow::Error> {
+    env::var(name).map_err(|e| match e {
+        env::VarError::NotPresent => {
+            anyhow!(
+                "environment variable {} used for {} is not set",
+                name,
+                field
+            )
+        }
+        env::VarError::NotUnicode(_) => anyhow!(
+            "environment variable {} used for {} is not valid unicode",
+            name,
+            field
+        ),
+    })
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Configuration {
     pub default_deploy_mode: Option<String>,
     #[serde(default)]
     pub build: BuildConfiguration,
+    /// Names for sets of modes which are deployed together.
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    /// Treats unused configuration keys as errors rather than warnings.
+    #[serde(default)]
+    pub strict: bool,
     #[serde(flatten)]
     pub modes: HashMap<String, ModeConfiguration>,
 }
 
 impl Configuration {
-    pub fn read<P: AsRef<Path>>(config_file: P) -> Result<Self, anyhow::Error> {
+    /// Reads the configuration, with `strict` (or `strict = true` in the file)
+    /// turning unused keys into an error.
+    pub fn read<P: AsRef<Path>>(config_file: P, strict: bool) -> Result<Self, anyhow::Error> {
         let config_file = config_file.as_ref();
+        Configuration::read_file(config_file, strict).context(Error::InvalidConfig {
+            path: config_file.to_owned(),
+        })
+    }
+
+    fn read_file(config_file: &Path, strict: bool) -> Result<Self, anyhow::Error> {
         ensure!(
             config_file.exists(),
             "expected configuration to exist at {}",
@@ -135,17 +545,1055 @@ impl Configuration {
         };
 
         let mut unused_paths = BTreeSet::new();
+        let track_unused = |unused_path: serde_ignored::Path| {
+            unused_paths.insert(unused_path.to_string());
+        };
 
-        let config: Configuration =
-            serde_ignored::deserialize(toml::Deserializer::new(&config_str), |unused_path| {
-                unused_paths.insert(unused_path.to_string());
-            })
-            .context("deserializing config")?;
+        let mut config_table: toml::Table =
+            toml::from_str(&config_str).context("deserializing config")?;
+        let overlays = read_credentials_overlays(config_file)?;
+        let extends_modes = config_table
+            .iter()
+            .any(|(_, value)| value.get("extends").is_some());
+
+        let mut ancestors = HashMap::new();
+        let mut config: Configuration = if overlays.is_empty() && !extends_modes {
+            // deserializing from the original text keeps line numbers in errors
+            serde_ignored::deserialize(toml::Deserializer::new(&config_str), track_unused)
+                .map_err(|e| explain_error(e.into(), &config_table, &ancestors, &config_table))?
+        } else {
+            for (overlay, path, existing_modes_only) in overlays {
+                debug!("merging credentials from {}", path.display());
+                merge_modes(&mut config_table, overlay, existing_modes_only);
+            }
+            let unresolved = config_table.clone();
+            ancestors = resolve_extends(&mut config_table)?;
+            serde_ignored::deserialize(toml::Value::Table(config_table.clone()), track_unused)
+                .map_err(|e| explain_error(e.into(), &config_table, &ancestors, &unresolved))?
+        };
+
+        // modes are read by way of a table, which hides their unused keys from
+        // serde_ignored
+        let mut untyped_modes = Vec::new();
+        for (name, mode) in &config_table {
+            if config.modes.contains_key(name) {
+                unused_mode_paths(name, mode, &mut unused_paths);
+                if mode.get("type").is_none() {
+                    untyped_modes.push(name.as_str());
+                }
+            }
+        }
+        if !untyped_modes.is_empty() {
+            untyped_modes.sort();
+            warn!(
+                "mode{} {} {} no type; guessing it from whether there's a destination is \
+                 deprecated, set type = \"copy\" or type = \"upload\"",
+                if untyped_modes.len() == 1 { "" } else { "s" },
+                untyped_modes.join(", "),
+                if untyped_modes.len() == 1 {
+                    "has"
+                } else {
+                    "have"
+                },
+            );
+        }
 
+        let unused_paths: Vec<String> = unused_paths
+            .iter()
+            .map(|path| match suggest_key(path) {
+                Some(suggestion) => format!("{path} (did you mean {suggestion}?)"),
+                None => path.clone(),
+            })
+            .collect();
+        if strict || config.strict {
+            ensure!(
+                unused_paths.is_empty(),
+                "unused configuration paths, which strict mode doesn't allow: {}",
+                unused_paths.join(", ")
+            );
+        }
         for path in &unused_paths {
             warn!("unused configuration path: {}", path)
         }
 
+        // fold in the build sections of the modes each mode extends, nearest
+        // first, so options are merged the same way as with the global section
+        let own_builds: HashMap<String, BuildConfiguration> = config
+            .modes
+            .iter()
+            .map(|(name, mode)| (name.clone(), mode.build().clone()))
+            .collect();
+        for (name, chain) in ancestors {
+            if let Some(mode) = config.modes.get_mut(&name) {
+                for ancestor in chain {
+                    if let Some(build) = own_builds.get(&ancestor) {
+                        mode.build_mut().merge(build.clone());
+                    }
+                }
+            }
+        }
+
+        // groups can include other groups, so long as none includes itself
+        for group in config.groups.keys() {
+            config.expand_groups(std::slice::from_ref(group))?;
+        }
+
         Ok(config)
     }
+
+    /// The name of the mode to use: `mode` if given, or `default_deploy_mode`.
+    pub fn mode_name(&self, mode: Option<&str>) -> Result<String, anyhow::Error> {
+        match mode {
+            Some(mode) => Ok(mode.to_owned()),
+            None => self.default_deploy_mode.clone().ok_or_else(|| {
+                anyhow!(
+                    "must have default_deploy_mode set to use 'cargo screeps deploy' without \
+                     --mode"
+                )
+            }),
+        }
+    }
+
+    /// A mode's options, with the global build section merged into its own.
+    pub fn mode(&self, name: &str) -> Result<ModeConfiguration, anyhow::Error> {
+        let mut mode = self
+            .modes
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::ModeNotFound {
+                mode: name.to_owned(),
+            })?;
+        mode.build_mut().merge(self.build.clone());
+
+        Ok(mode)
+    }
+
+    /// Replaces the names of groups of modes with the modes in them, including
+    /// those in groups within groups, leaving out modes which are named more
+    /// than once. A name which is both a mode and a group refers to the mode.
+    pub fn expand_groups(&self, names: &[String]) -> Result<Vec<String>, anyhow::Error> {
+        let mut modes = Vec::new();
+        self.expand_groups_into(names, &mut Vec::new(), &mut modes)?;
+
+        Ok(modes)
+    }
+
+    fn expand_groups_into(
+        &self,
+        names: &[String],
+        expanding: &mut Vec<String>,
+        modes: &mut Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        for name in names {
+            match self.groups.get(name) {
+                Some(members) if !self.modes.contains_key(name) => {
+                    if let Some(start) = expanding.iter().position(|group| group == name) {
+                        bail!(
+                            "group {} includes itself: {} -> {}",
+                            name,
+                            expanding[start..].join(" -> "),
+                            name
+                        );
+                    }
+                    expanding.push(name.clone());
+                    self.expand_groups_into(members, expanding, modes)?;
+                    expanding.pop();
+                }
+                _ => {
+                    if !modes.contains(name) {
+                        modes.push(name.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const COPY_FIELDS: &[&str] = &["destination", "branch", "build", "include_files", "prune"];
+
+const UPLOAD_FIELDS: &[&str] = &[
+    "auth_token",
+    "auth_token_env",
+    "username",
+    "password",
+    "branch",
+    "build",
+    "include_files",
+    "hostname",
+    "ssl",
+    "port",
+    "prefix",
+    "http_timeout",
+    "max_attempts",
+    "retry_backoff",
+    "shard",
+];
+
+const BUILD_FIELDS: &[&str] = &[
+    "build_profile",
+    "build_mode",
+    "out_name",
+    "extra_options",
+    "path",
+    "strip_name_section",
+    "size_budget",
+    "wasm_opt",
+    "build_info",
+];
+
+/// Adds the keys of a mode which none of its variant's fields read, including
+/// those in its `build` section.
+fn unused_mode_paths(name: &str, mode: &toml::Value, unused_paths: &mut BTreeSet<String>) {
+    let toml::Value::Table(mode) = mode else {
+        return;
+    };
+    let fields = match mode.get("type").and_then(toml::Value::as_str) {
+        Some("copy") => COPY_FIELDS,
+        Some(_) => UPLOAD_FIELDS,
+        None if mode.contains_key("destination") => COPY_FIELDS,
+        None => UPLOAD_FIELDS,
+    };
+    for key in mode.keys() {
+        if !fields.contains(&key.as_str()) && key != "extends" && key != "type" {
+            unused_paths.insert(format!("{name}.{key}"));
+        }
+    }
+    if let Some(build) = mode.get("build") {
+        let _: Result<BuildConfiguration, _> =
+            serde_ignored::deserialize(build.clone(), |path: serde_ignored::Path| {
+                unused_paths.insert(format!("{name}.build.{path}"));
+            });
+    }
+}
+
+/// A known key close enough to the last part of an unused path that it's
+/// likely a typo of it.
+fn suggest_key(path: &str) -> Option<&'static str> {
+    let key = path.rsplit('.').next().unwrap_or(path);
+    COPY_FIELDS
+        .iter()
+        .chain(UPLOAD_FIELDS)
+        .chain(BUILD_FIELDS)
+        .map(|field| (edit_distance(key, field), *field))
+        .filter(|&(distance, _)| distance > 0 && distance <= 2 && distance < key.len())
+        .min()
+        .map(|(_, field)| field)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Adds an explanation of why each mode that doesn't deserialize fails to a
+/// deserialization error, since the untagged mode enum only says that no
+/// variant matched, along with where the options of modes which `extends`
+/// others came from.
+fn explain_error(
+    error: anyhow::Error,
+    config: &toml::Table,
+    ancestors: &HashMap<String, Vec<String>>,
+    unresolved: &toml::Table,
+) -> anyhow::Error {
+    let mut explanations = Vec::new();
+    for (name, explanation) in explain_named_modes(config) {
+        explanations.push(format!("mode {name}: {explanation}"));
+        if let Some(chain) = ancestors.get(&name).filter(|chain| !chain.is_empty()) {
+            explanations.push(explain_extends(&name, chain, unresolved));
+        }
+    }
+    if explanations.is_empty() {
+        return error.context("deserializing config");
+    }
+    error.context(format!(
+        "deserializing config:\n  {}",
+        explanations.join("\n  ")
+    ))
+}
+
+/// Explains, for each mode that can't be read, which kind of mode it was read
+/// as and what's missing or wrong.
+pub fn explain_modes(config: &toml::Table) -> Vec<String> {
+    explain_named_modes(config)
+        .into_iter()
+        .map(|(name, explanation)| format!("mode {name}: {explanation}"))
+        .collect()
+}
+
+fn explain_named_modes(config: &toml::Table) -> Vec<(String, String)> {
+    let mut names: Vec<&String> = config.keys().collect();
+    names.sort();
+
+    let mut explanations = Vec::new();
+    for name in names {
+        let value = &config[name];
+        if matches!(
+            name.as_str(),
+            "build" | "groups" | "default_deploy_mode" | "strict"
+        ) {
+            continue;
+        }
+        if let Some(explanation) = explain_mode(value) {
+            explanations.push((name.clone(), explanation));
+        }
+    }
+    explanations
+}
+
+/// Says which of the modes a mode extends each of its inherited options came
+/// from, so an explanation about an inherited option points at the mode to
+/// fix.
+fn explain_extends(name: &str, chain: &[String], unresolved: &toml::Table) -> String {
+    let keys = |mode: &str| -> Vec<String> {
+        match unresolved.get(mode) {
+            Some(toml::Value::Table(mode)) => mode
+                .keys()
+                .filter(|key| *key != "extends" && *key != "build")
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        }
+    };
+
+    let mut seen = keys(name);
+    let mut origins = Vec::new();
+    for ancestor in chain {
+        let mut inherited: Vec<String> = keys(ancestor)
+            .into_iter()
+            .filter(|key| !seen.contains(key))
+            .collect();
+        if inherited.is_empty() {
+            continue;
+        }
+        inherited.sort();
+        seen.extend(inherited.iter().cloned());
+        origins.push(format!("{} from {}", inherited.join(", "), ancestor));
+    }
+
+    let mut explanation = format!("mode {name} extends {}", chain.join(", which extends "));
+    if !origins.is_empty() {
+        explanation.push_str(&format!("; it gets {}", origins.join(", and ")));
+    }
+    explanation
+}
+
+fn explain_mode(value: &toml::Value) -> Option<String> {
+    let toml::Value::Table(mode) = value else {
+        return Some(format!("expected a table, found {}", value.type_str()));
+    };
+    if value.clone().try_into::<ModeConfiguration>().is_ok() {
+        return None;
+    }
+
+    let mut mode = mode.clone();
+    let (is_copy, read_as) = match mode.remove("type") {
+        Some(toml::Value::String(mode_type)) if mode_type == "copy" => (true, "is a copy mode"),
+        Some(toml::Value::String(mode_type)) if mode_type == "upload" => {
+            (false, "is an upload mode")
+        }
+        Some(other) => {
+            return Some(format!(
+                "type must be one of \"{}\", found {}",
+                MODE_TYPES.join("\", \""),
+                other
+            ))
+        }
+        None if mode.contains_key("destination") => {
+            (true, "read as a copy mode since it has a destination")
+        }
+        None => (false, "read as an upload mode"),
+    };
+
+    if is_copy {
+        return Some(format!(
+            "{read_as}, but {}",
+            field_error::<CopyConfiguration>(&mode)?
+        ));
+    }
+
+    let has_auth = ["auth_token", "auth_token_env", "username", "password"]
+        .iter()
+        .any(|key| mode.contains_key(*key));
+    if !has_auth {
+        let candidates: &[&str] = if value.get("type").is_some() {
+            &["auth_token", "auth_token_env", "username", "password"]
+        } else {
+            &[
+                "destination",
+                "auth_token",
+                "auth_token_env",
+                "username",
+                "password",
+            ]
+        };
+        let hint = mode
+            .keys()
+            .filter_map(|key| {
+                candidates
+                    .iter()
+                    .find(|field| (1..=2).contains(&edit_distance(key, field)))
+                    .map(|field| format!(" (did you mean {field} instead of {key}?)"))
+            })
+            .next()
+            .unwrap_or_default();
+        return Some(if value.get("type").is_some() {
+            format!(
+                "{read_as}, but needs auth_token, auth_token_env, or username and password{hint}"
+            )
+        } else {
+            format!(
+                "needs either a destination to be a copy mode, or auth_token, auth_token_env, or \
+                 username and password to be an upload mode{hint}"
+            )
+        });
+    }
+
+    match (mode.contains_key("username"), mode.contains_key("password")) {
+        (true, false) => return Some(format!("{read_as}, but username is set without password")),
+        (false, true) => return Some(format!("{read_as}, but password is set without username")),
+        _ => {}
+    }
+
+    // the flattened, untagged authentication hides which field is wrong, so
+    // try the authentication and server sections separately
+    let mode_value = toml::Value::Table(mode.clone());
+    let rest = if mode_value
+        .try_into::<AuthenticationConfiguration>()
+        .is_err()
+    {
+        "its credentials must be strings".to_owned()
+    } else {
+        field_error::<ServerConfiguration>(&mode)
+            .or_else(|| field_error::<UploadConfiguration>(&mode))
+            .unwrap_or_else(|| "it doesn't match any kind of mode".to_owned())
+    };
+    Some(format!("{read_as}, but {rest}"))
+}
+
+/// The error from reading a mode as `T`, naming the key it's about; reading
+/// from text rather than a value gives the error a position to find it by.
+fn field_error<T: serde::de::DeserializeOwned>(mode: &toml::Table) -> Option<String> {
+    let text = toml::to_string(mode).ok()?;
+    let error = toml::from_str::<T>(&text).err()?;
+    let key = error
+        .span()
+        .and_then(|span| text[..span.start].lines().last())
+        .and_then(|line| line.split_once('='))
+        .map(|(key, _)| key.trim())
+        .filter(|key| !key.is_empty());
+
+    Some(match key {
+        Some(key) => format!("{key}: {}", error.message().trim()),
+        None => error.message().trim().to_owned(),
+    })
+}
+
+/// Replaces each mode which `extends` another with the other mode's options,
+/// overridden by its own; modes can extend modes which extend others in turn.
+///
+/// `build` tables are left as they are, to be merged after deserializing;
+/// returns the modes each mode extends, nearest first.
+fn resolve_extends(
+    config: &mut toml::Table,
+) -> Result<HashMap<String, Vec<String>>, anyhow::Error> {
+    let mut parents = HashMap::new();
+    for (name, value) in config.iter_mut() {
+        let toml::Value::Table(mode) = value else {
+            continue;
+        };
+        if name == "build" || name == "groups" {
+            continue;
+        }
+        match mode.remove("extends") {
+            Some(toml::Value::String(parent)) => {
+                parents.insert(name.clone(), parent);
+            }
+            Some(_) => bail!(
+                "'extends' in mode {} must be the name of another mode",
+                name
+            ),
+            None => {}
+        }
+    }
+
+    let mut ancestors = HashMap::new();
+    for name in parents.keys() {
+        let mut chain: Vec<String> = Vec::new();
+        let mut current = name;
+        while let Some(parent) = parents.get(current) {
+            if parent == name || chain.contains(parent) {
+                let cycle: Vec<&str> = std::iter::once(name)
+                    .chain(&chain)
+                    .chain(std::iter::once(parent))
+                    .map(String::as_str)
+                    .collect();
+                bail!("modes extend each other in a cycle: {}", cycle.join(" -> "));
+            }
+            ensure!(
+                parent != "build"
+                    && parent != "groups"
+                    && matches!(config.get(parent), Some(toml::Value::Table(_))),
+                "mode {} extends {}, which isn't defined in screeps.toml",
+                current,
+                parent
+            );
+            chain.push(parent.clone());
+            current = parent;
+        }
+        ancestors.insert(name.clone(), chain);
+    }
+
+    let original = config.clone();
+    for (name, chain) in &ancestors {
+        let mut resolved = toml::Table::new();
+        for mode in chain.iter().rev().chain(std::iter::once(name)) {
+            if let Some(toml::Value::Table(mode)) = original.get(mode) {
+                let mut mode = mode.clone();
+                mode.remove("build");
+                merge_tables(&mut resolved, mode);
+            }
+        }
+        if let Some(build) = original.get(name).and_then(|mode| mode.get("build")) {
+            resolved.insert("build".to_owned(), build.clone());
+        }
+        config.insert(name.clone(), toml::Value::Table(resolved));
+    }
+
+    Ok(ancestors)
+}
+
+/// Reads the files which can hold credentials outside of `screeps.toml`, in the
+/// order they're merged in: the user's `credentials.toml`, which only fills in
+/// modes the project defines, then `screeps.local.toml` next to the config file.
+fn read_credentials_overlays(
+    config_file: &Path,
+) -> Result<Vec<(toml::Table, PathBuf, bool)>, anyhow::Error> {
+    let candidates = [
+        (
+            user_config_dir().map(|dir| dir.join("cargo-screeps").join("credentials.toml")),
+            true,
+        ),
+        (Some(local_config_path(config_file)), false),
+    ];
+
+    let mut overlays = Vec::new();
+    for (path, existing_modes_only) in candidates {
+        let Some(path) = path.filter(|path| path.is_file()) else {
+            continue;
+        };
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let table = toml::from_str(&contents)
+            .with_context(|| format!("deserializing {}", path.display()))?;
+        overlays.push((table, path, existing_modes_only));
+    }
+
+    Ok(overlays)
+}
+
+/// The untracked file next to the config file, which can hold credentials and
+/// other local overrides.
+pub fn local_config_path(config_file: &Path) -> PathBuf {
+    config_file.with_file_name("screeps.local.toml")
+}
+
+fn user_config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+}
+
+/// Merges the tables of an overlay file into the configuration, with the
+/// overlay's values taking priority.
+fn merge_modes(config: &mut toml::Table, overlay: toml::Table, existing_modes_only: bool) {
+    for (key, value) in overlay {
+        match (config.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(overlay)) => {
+                merge_tables(existing, overlay)
+            }
+            (None, _) if existing_modes_only => {}
+            (_, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(overlay)) => {
+                merge_tables(existing, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: &str = r#"
+        [mmo]
+        type = "copy"
+        destination = "mmo"
+
+        [season]
+        type = "copy"
+        destination = "season"
+
+        [ptr]
+        type = "copy"
+        destination = "ptr"
+        "#;
+
+    /// The user's `credentials.toml` for every test, which fills in the
+    /// `overlaid` and `local_first` modes, instead of whatever the user running
+    /// the tests has.
+    const USER_CREDENTIALS: &str = r#"
+        [overlaid]
+        auth_token = "credentials"
+
+        [local_first]
+        auth_token = "credentials"
+
+        [absent]
+        type = "copy"
+        destination = "absent"
+        "#;
+
+    fn set_user_config_dir() {
+        static SET: std::sync::Once = std::sync::Once::new();
+        SET.call_once(|| {
+            let dir =
+                env::temp_dir().join(format!("cargo-screeps-config-home-{}", std::process::id()));
+            fs::create_dir_all(dir.join("cargo-screeps")).unwrap();
+            fs::write(
+                dir.join("cargo-screeps").join("credentials.toml"),
+                USER_CREDENTIALS,
+            )
+            .unwrap();
+            env::set_var("XDG_CONFIG_HOME", dir);
+        });
+    }
+
+    /// Reads `screeps.toml` from a fresh directory holding the given files.
+    fn read_config(test: &str, files: &[(&str, &str)]) -> Result<Configuration, anyhow::Error> {
+        set_user_config_dir();
+        let dir = env::temp_dir().join(format!(
+            "cargo-screeps-config-{}-{}",
+            test,
+            std::process::id()
+        ));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let config = Configuration::read(dir.join("screeps.toml"), false);
+        fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    #[test]
+    fn expands_nested_groups() {
+        let config = read_config(
+            "expands-nested-groups",
+            &[(
+                "screeps.toml",
+                &format!(
+                    "[groups]\nlive = [\"mmo\", \"testing\"]\ntesting = [\"ptr\", \"mmo\", \"season\"]\n{MODES}"
+                ),
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.expand_groups(&["live".to_owned()]).unwrap(),
+            ["mmo", "ptr", "season"]
+        );
+    }
+
+    #[test]
+    fn rejects_group_cycles() {
+        let error = read_config(
+            "rejects-group-cycles",
+            &[(
+                "screeps.toml",
+                &format!("[groups]\nlive = [\"mmo\", \"all\"]\nall = [\"live\"]\n{MODES}"),
+            )],
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            Error::find(&error),
+            Some(Error::InvalidConfig { .. })
+        ));
+        assert!(format!("{error:#}").contains("includes itself"));
+    }
+
+    #[test]
+    fn resolves_extends_through_grandparents() {
+        let text = r#"
+            [base]
+            type = "copy"
+            destination = "base"
+            branch = "base"
+
+            [middle]
+            extends = "base"
+            branch = "middle"
+            prune = false
+
+            [leaf]
+            extends = "middle"
+            destination = "leaf"
+            "#;
+
+        let mut table: toml::Table = toml::from_str(text).unwrap();
+        let ancestors = resolve_extends(&mut table).unwrap();
+        assert_eq!(ancestors["leaf"], ["middle", "base"]);
+        assert_eq!(ancestors["middle"], ["base"]);
+        assert!(!ancestors.contains_key("base"));
+
+        let config = read_config("resolves-extends", &[("screeps.toml", text)]).unwrap();
+        let ModeConfiguration::Copy(leaf) = config.mode("leaf").unwrap() else {
+            panic!("expected leaf to be a copy mode");
+        };
+        assert_eq!(leaf.destination, Path::new("leaf"));
+        assert_eq!(leaf.branch, "middle");
+        assert!(!leaf.prune);
+    }
+
+    #[test]
+    fn extends_merges_nested_build_options() {
+        let config = read_config(
+            "extends-merges-build",
+            &[(
+                "screeps.toml",
+                r#"
+                [base]
+                type = "copy"
+                destination = "base"
+
+                [base.build]
+                build_profile = "dev"
+                out_name = "base"
+
+                [base.build.wasm_opt]
+                enabled = true
+                level = "Oz"
+
+                [child]
+                extends = "base"
+
+                [child.build]
+                out_name = "child"
+
+                [child.build.wasm_opt]
+                level = "O2"
+                "#,
+            )],
+        )
+        .unwrap();
+
+        let build = config.mode("child").unwrap().build().clone();
+        assert_eq!(build.build_profile, Some(BuildProfile::Dev));
+        assert_eq!(build.out_name.as_deref(), Some("child"));
+        assert_eq!(build.wasm_opt.enabled, Some(true));
+        assert_eq!(build.wasm_opt.level, Some(WasmOptLevel::O2));
+        assert_eq!(
+            config.mode("base").unwrap().build().out_name.as_deref(),
+            Some("base")
+        );
+    }
+
+    #[test]
+    fn rejects_extends_cycles() {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+            [a]
+            type = "copy"
+            destination = "a"
+            extends = "b"
+
+            [b]
+            type = "copy"
+            destination = "b"
+            extends = "a"
+            "#,
+        )
+        .unwrap();
+
+        let error = resolve_extends(&mut table).unwrap_err().to_string();
+        assert!(
+            error == "modes extend each other in a cycle: a -> b -> a"
+                || error == "modes extend each other in a cycle: b -> a -> b",
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn rejects_extending_unknown_modes() {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+            [ptr]
+            extends = "mmo"
+            branch = "ptr"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            resolve_extends(&mut table).unwrap_err().to_string(),
+            "mode ptr extends mmo, which isn't defined in screeps.toml"
+        );
+    }
+
+    fn auth_token(config: &Configuration, mode: &str) -> Result<String, anyhow::Error> {
+        let ModeConfiguration::Upload(upload) = config.mode(mode)? else {
+            panic!("expected {mode} to be an upload mode");
+        };
+        match upload.authentication.resolve()? {
+            Authentication::Token { auth_token } => Ok(auth_token),
+            Authentication::Basic { .. } => panic!("expected {mode} to have an auth token"),
+        }
+    }
+
+    #[test]
+    fn interpolates_set_variables() {
+        env::set_var("CARGO_SCREEPS_TEST_SET", "abc");
+
+        assert_eq!(
+            interpolate_env("${CARGO_SCREEPS_TEST_SET}", "auth_token").unwrap(),
+            "abc"
+        );
+        assert_eq!(
+            interpolate_env(
+                "x-${CARGO_SCREEPS_TEST_SET}-${CARGO_SCREEPS_TEST_SET}",
+                "password"
+            )
+            .unwrap(),
+            "x-abc-abc"
+        );
+        assert_eq!(interpolate_env("$abc {x}", "password").unwrap(), "$abc {x}");
+    }
+
+    #[test]
+    fn rejects_unset_variables() {
+        assert_eq!(
+            interpolate_env("${CARGO_SCREEPS_TEST_UNSET}", "password")
+                .unwrap_err()
+                .to_string(),
+            "environment variable CARGO_SCREEPS_TEST_UNSET used for password is not set"
+        );
+    }
+
+    #[test]
+    fn leaves_escaped_references() {
+        env::set_var("CARGO_SCREEPS_TEST_ESCAPED", "abc");
+
+        assert_eq!(
+            interpolate_env(
+                "$${CARGO_SCREEPS_TEST_ESCAPED}/${CARGO_SCREEPS_TEST_ESCAPED}",
+                "password"
+            )
+            .unwrap(),
+            "${CARGO_SCREEPS_TEST_ESCAPED}/abc"
+        );
+        assert_eq!(
+            interpolate_env("$${CARGO_SCREEPS_TEST_UNSET}", "password").unwrap(),
+            "${CARGO_SCREEPS_TEST_UNSET}"
+        );
+    }
+
+    #[test]
+    fn interpolates_variables_in_modes() {
+        env::set_var("CARGO_SCREEPS_TEST_MODE_TOKEN", "abc");
+
+        let config = read_config(
+            "interpolates-variables-in-modes",
+            &[(
+                "screeps.toml",
+                r#"
+                [mmo]
+                type = "upload"
+                auth_token = "${CARGO_SCREEPS_TEST_MODE_TOKEN}"
+
+                [mmo.build]
+                out_name = "mmo"
+
+                [ptr]
+                extends = "mmo"
+                prefix = "ptr"
+                "#,
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(auth_token(&config, "mmo").unwrap(), "abc");
+        assert_eq!(auth_token(&config, "ptr").unwrap(), "abc");
+    }
+
+    #[test]
+    fn credentials_overlays_take_precedence() {
+        let config = read_config(
+            "credentials-overlays-take-precedence",
+            &[
+                (
+                    "screeps.toml",
+                    r#"
+                    [overlaid]
+                    type = "upload"
+                    auth_token = "base"
+
+                    [local_first]
+                    type = "upload"
+                    auth_token = "base"
+
+                    [base_only]
+                    type = "upload"
+                    auth_token = "base"
+                    "#,
+                ),
+                (
+                    "screeps.local.toml",
+                    r#"
+                    [local_first]
+                    auth_token = "local"
+                    "#,
+                ),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(auth_token(&config, "base_only").unwrap(), "base");
+        assert_eq!(auth_token(&config, "overlaid").unwrap(), "credentials");
+        assert_eq!(auth_token(&config, "local_first").unwrap(), "local");
+        // the user's credentials only fill in modes the project has
+        assert!(!config.modes.contains_key("absent"));
+    }
+
+    #[test]
+    fn suggests_keys_for_typos() {
+        assert_eq!(suggest_key("mmo.destinaton"), Some("destination"));
+        assert_eq!(suggest_key("mmo.auth_tokn"), Some("auth_token"));
+        assert_eq!(suggest_key("mmo.build.out_nam"), Some("out_name"));
+        assert_eq!(suggest_key("mmo.colour"), None);
+        // a known key isn't a typo of another
+        assert_eq!(suggest_key("mmo.branch"), None);
+    }
+
+    #[test]
+    fn explains_typos_in_modes() {
+        let explanations = explain_modes(
+            &toml::from_str(
+                r#"
+                [mmo]
+                type = "upload"
+                auth_tokn = "abc"
+
+                [local]
+                destinaton = "out"
+                "#,
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(
+            explanations,
+            [
+                "mode local: needs either a destination to be a copy mode, or auth_token, \
+                 auth_token_env, or username and password to be an upload mode (did you mean \
+                 destination instead of destinaton?)",
+                "mode mmo: is an upload mode, but needs auth_token, auth_token_env, or username \
+                 and password (did you mean auth_token instead of auth_tokn?)",
+            ]
+        );
+    }
+
+    #[test]
+    fn explains_where_extended_options_come_from() {
+        let error = read_config(
+            "explains-extended-options",
+            &[(
+                "screeps.toml",
+                r#"
+                [base]
+                type = "upload"
+                auth_token = "abc"
+                port = "eighty"
+
+                [middle]
+                extends = "base"
+                hostname = "localhost"
+
+                [leaf]
+                extends = "middle"
+                branch = "leaf"
+                "#,
+            )],
+        )
+        .unwrap_err();
+        let error = format!("{error:#}");
+
+        assert!(
+            error.contains(
+                "mode leaf: is an upload mode, but port: invalid type: string \"eighty\", \
+                 expected u16\n  mode leaf extends middle, which extends base; it gets hostname \
+                 from middle, and auth_token, port, type from base\n"
+            ),
+            "{error}"
+        );
+        assert!(
+            error.contains("mode middle extends base; it gets auth_token, port, type from base"),
+            "{error}"
+        );
+        assert!(!error.contains("mode base extends"), "{error}");
+    }
+
+    #[test]
+    fn serializes_mode_without_unset_options_or_credentials() {
+        let mode: ModeConfiguration = toml::from_str(
+            r#"
+            type = "upload"
+            username = "bot"
+            password = "hunter2"
+            shard = "shard3"
+
+            [build.wasm_opt]
+            enabled = true
+            "#,
+        )
+        .unwrap();
+
+        let shown = toml::to_string(&mode).unwrap();
+
+        assert!(shown.starts_with("type = \"upload\"\nusername = \"bot\"\n"));
+        assert!(shown.contains("password = \"<redacted>\"\n"));
+        assert!(!shown.contains("hunter2"));
+        assert!(shown.contains("shard = \"shard3\"\n"));
+        assert!(!shown.contains("prefix"));
+        assert!(!shown.contains("build_profile"));
+        assert!(shown.contains("[build.wasm_opt]\nenabled = true\n"));
+        assert!(!shown.contains("[build.build_info]"));
+    }
 }
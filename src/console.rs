@@ -0,0 +1,411 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context};
+use base64::Engine;
+use log::*;
+use reqwest::blocking::Client;
+use serde::Serialize;
+use serde_json::json;
+use tungstenite::Message;
+
+use crate::{
+    config::{Authentication, ServerConfiguration},
+    progress, redact,
+    setup::MessageFormat,
+    upload,
+};
+
+/// Streams the account's console output from the server, running any
+/// expressions read from stdin in the console as well.
+///
+/// Runs until the connection is closed. With `--message-format json`, prints
+/// each line of output as a `console-output` event.
+pub fn console(
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    shard: Option<&str>,
+    message_format: MessageFormat,
+) -> Result<(), anyhow::Error> {
+    let client = upload::client(server)?;
+    let token = socket_token(&client, authentication, server)?;
+    let user_id = user_id(&client, authentication, server)?;
+
+    spawn_expression_reader(
+        client.clone(),
+        authentication.clone(),
+        server.clone(),
+        shard.map(Into::into),
+    );
+
+    let url = server.socket_url();
+    debug!("connecting to {}", url);
+    let (mut socket, _) =
+        tungstenite::connect(&url).with_context(|| format!("connecting to '{url}'"))?;
+
+    socket.send(Message::Text(format!("auth {token}")))?;
+
+    let channel = format!("user:{user_id}/console");
+
+    loop {
+        let text = match socket.read().context("reading from console connection")? {
+            Message::Text(text) => text,
+            Message::Close(_) => bail!("server closed the console connection"),
+            _ => continue,
+        };
+
+        match parse_frame(text, &channel)? {
+            Frame::Authenticated(true) => {
+                socket.send(Message::Text(format!("subscribe {channel}")))?;
+                info!(
+                    "streaming console from {}, type expressions to run them",
+                    url
+                );
+            }
+            Frame::Authenticated(false) => {
+                bail!("console connection to '{}' failed to authenticate", url)
+            }
+            Frame::Console(data) => {
+                let lines = console_lines(&data, |shard| {
+                    game_time(&client, server, shard)
+                        .map_err(|e| debug!("couldn't get game time: {:#}", e))
+                        .ok()
+                });
+                for line in lines {
+                    match message_format {
+                        MessageFormat::Human => println!("{line}"),
+                        MessageFormat::Json => progress::print_json(&line),
+                    }
+                }
+            }
+            Frame::Other => {}
+        }
+    }
+}
+
+/// A message received on the console connection.
+#[derive(Debug)]
+enum Frame {
+    /// The response to authenticating, and whether it succeeded.
+    Authenticated(bool),
+    /// An event on the console channel.
+    Console(serde_json::Value),
+    /// Anything else, such as events on other channels, the server time or
+    /// the protocol version.
+    Other,
+}
+
+fn parse_frame(text: String, channel: &str) -> Result<Frame, anyhow::Error> {
+    let text = decompress(text)?;
+
+    if let Some(result) = text.strip_prefix("auth ") {
+        return Ok(Frame::Authenticated(result.starts_with("ok")));
+    }
+
+    // everything other than channel events is plain text
+    if !text.starts_with('[') {
+        trace!("console connection: {}", text);
+        return Ok(Frame::Other);
+    }
+
+    let (event_channel, data): (String, serde_json::Value) =
+        serde_json::from_str(&text).context("parsing console event")?;
+    if event_channel == channel {
+        Ok(Frame::Console(data))
+    } else {
+        Ok(Frame::Other)
+    }
+}
+
+/// Gets a token to authenticate the websocket connection with; auth tokens can
+/// be used directly, while username and password need to sign in first.
+fn socket_token(
+    client: &Client,
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+) -> Result<String, anyhow::Error> {
+    match authentication {
+        Authentication::Token { auth_token } => Ok(auth_token.clone()),
+        Authentication::Basic { username, password } => {
+            let url = server.api_url("auth/signin");
+            let response = upload::send_with_retries(server, || {
+                client
+                    .post(&url)
+                    .json(&json!({ "email": username, "password": password }))
+            })?;
+            let response_json = upload::api_response(response, "signing in")?;
+
+            let token = response_json
+                .get("token")
+                .and_then(serde_json::Value::as_str)
+                .ok_or_else(|| anyhow!("signing in on '{}' returned no token", url))?;
+            redact::register_secret(token);
+
+            Ok(token.to_owned())
+        }
+    }
+}
+
+fn user_id(
+    client: &Client,
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+) -> Result<String, anyhow::Error> {
+    let url = server.api_url("auth/me");
+    let response = upload::send_with_retries(server, || {
+        upload::authenticate(client.get(&url), authentication)
+    })?;
+    let response_json = upload::api_response(response, "looking up user")?;
+
+    response_json
+        .get("_id")
+        .and_then(serde_json::Value::as_str)
+        .map(Into::into)
+        .ok_or_else(|| anyhow!("response from '{}' contained no user id", url))
+}
+
+/// Some events are sent zlib-compressed and base64-encoded, marked with a `gz:`
+/// prefix.
+fn decompress(text: String) -> Result<String, anyhow::Error> {
+    let Some(compressed) = text.strip_prefix("gz:") else {
+        return Ok(text);
+    };
+
+    let compressed = base64::engine::general_purpose::STANDARD
+        .decode(compressed)
+        .context("decoding compressed console event")?;
+    let mut decompressed = String::new();
+    flate2::read::ZlibDecoder::new(&compressed[..])
+        .read_to_string(&mut decompressed)
+        .context("decompressing console event")?;
+
+    Ok(decompressed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputKind {
+    Log,
+    Result,
+    Error,
+}
+
+/// A line of console output, printed with its shard and tick.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "reason", rename = "console-output")]
+struct OutputLine {
+    shard: Option<String>,
+    tick: Option<u64>,
+    kind: OutputKind,
+    line: String,
+}
+
+impl fmt::Display for OutputLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.shard, self.tick) {
+            (Some(shard), Some(tick)) => write!(f, "[{shard} {tick}] ")?,
+            (Some(shard), None) => write!(f, "[{shard}] ")?,
+            (None, Some(tick)) => write!(f, "[{tick}] ")?,
+            (None, None) => {}
+        }
+        match self.kind {
+            OutputKind::Log => write!(f, "{}", self.line),
+            OutputKind::Result => write!(f, "< {}", self.line),
+            OutputKind::Error => write!(f, "error: {}", self.line),
+        }
+    }
+}
+
+/// Splits a console event into lines of output.
+///
+/// Console events don't carry the tick, so the lines are labelled with the one
+/// `game_time` gives for the event's shard, looked up once for each event with
+/// any output.
+fn console_lines(
+    data: &serde_json::Value,
+    game_time: impl FnOnce(Option<&str>) -> Option<u64>,
+) -> Vec<OutputLine> {
+    let shard = data.get("shard").and_then(serde_json::Value::as_str);
+
+    let lines = |key| {
+        data.get("messages")
+            .and_then(|messages| messages.get(key))
+            .and_then(serde_json::Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_str)
+            .flat_map(str::lines)
+    };
+    let error = data.get("error").and_then(serde_json::Value::as_str);
+    let output: Vec<_> = lines("log")
+        .map(|line| (OutputKind::Log, line))
+        .chain(lines("results").map(|line| (OutputKind::Result, line)))
+        .chain(
+            error
+                .into_iter()
+                .flat_map(str::lines)
+                .map(|line| (OutputKind::Error, line)),
+        )
+        .collect();
+    if output.is_empty() {
+        return Vec::new();
+    }
+
+    let tick = game_time(shard);
+    output
+        .into_iter()
+        .map(|(kind, line)| OutputLine {
+            shard: shard.map(Into::into),
+            tick,
+            kind,
+            line: line.to_owned(),
+        })
+        .collect()
+}
+
+fn game_time(
+    client: &Client,
+    server: &ServerConfiguration,
+    shard: Option<&str>,
+) -> Result<u64, anyhow::Error> {
+    let mut request = client.get(server.api_url("game/time"));
+    if let Some(shard) = shard {
+        request = request.query(&[("shard", shard)]);
+    }
+    let response_json = upload::api_response(request.send()?, "getting game time")?;
+
+    response_json
+        .get("time")
+        .and_then(serde_json::Value::as_u64)
+        .ok_or_else(|| anyhow!("game time response contained no time"))
+}
+
+/// Reads expressions from stdin on a background thread, sending each line to be
+/// run in the console. Results arrive through the console stream.
+fn spawn_expression_reader(
+    client: Client,
+    authentication: Authentication,
+    server: ServerConfiguration,
+    shard: Option<String>,
+) {
+    thread::spawn(move || {
+        let url = server.api_url("user/console");
+
+        for line in io::stdin().lock().lines() {
+            let expression = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("stopped reading expressions: {}", e);
+                    return;
+                }
+            };
+
+            // sent once, since retrying could run the expression twice
+            let result = upload::authenticate(client.post(&url), &authentication)
+                .json(&json!({ "expression": expression, "shard": shard }))
+                .send()
+                .map_err(anyhow::Error::from)
+                .and_then(|response| upload::api_response(response, "running expression"));
+
+            if let Err(e) = result {
+                error!("{:#}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const CHANNEL: &str = "user:u1/console";
+
+    fn compress(text: &str) -> String {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        format!(
+            "gz:{}",
+            base64::engine::general_purpose::STANDARD.encode(compressed)
+        )
+    }
+
+    /// Runs frames through the console, as they'd arrive on the connection,
+    /// with the game time reported as the next of `times` each time it's
+    /// looked up.
+    fn output(frames: &[String], times: &[Option<u64>]) -> Vec<String> {
+        let mut times = times.iter();
+        let mut output = Vec::new();
+        for frame in frames {
+            if let Frame::Console(data) = parse_frame(frame.clone(), CHANNEL).unwrap() {
+                let lines = console_lines(&data, |_| *times.next().expect("unexpected lookup"));
+                output.extend(lines.iter().map(ToString::to_string));
+            }
+        }
+        assert_eq!(times.next(), None, "game time looked up too few times");
+        output
+    }
+
+    #[test]
+    fn parses_frames() {
+        assert!(matches!(
+            parse_frame("time 1700000000".into(), CHANNEL).unwrap(),
+            Frame::Other
+        ));
+        assert!(matches!(
+            parse_frame("auth ok token".into(), CHANNEL).unwrap(),
+            Frame::Authenticated(true)
+        ));
+        assert!(matches!(
+            parse_frame("auth failed".into(), CHANNEL).unwrap(),
+            Frame::Authenticated(false)
+        ));
+        assert!(matches!(
+            parse_frame(r#"["user:u2/console",{"shard":"shard0"}]"#.into(), CHANNEL).unwrap(),
+            Frame::Other
+        ));
+    }
+
+    #[test]
+    fn prefixes_lines_with_shard_and_tick() {
+        let frames = [
+            r#"["user:u1/console",{"messages":{"log":["hello\nworld"],"results":["2"]},"shard":"shard0"}]"#.to_owned(),
+            compress(r#"["user:u1/console",{"error":"panicked\n  at src/lib.rs","shard":"shard1"}]"#),
+            r#"["user:u1/console",{"messages":{"log":["tick"],"results":[]}}]"#.to_owned(),
+        ];
+
+        assert_eq!(
+            output(&frames, &[Some(100), Some(7), None]),
+            [
+                "[shard0 100] hello",
+                "[shard0 100] world",
+                "[shard0 100] < 2",
+                "[shard1 7] error: panicked",
+                "[shard1 7] error:   at src/lib.rs",
+                "tick",
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_each_event_with_the_game_time() {
+        let event =
+            r#"["user:u1/console",{"messages":{"log":["log"],"results":[]},"shard":"shard0"}]"#;
+        let empty = r#"["user:u1/console",{"messages":{"log":[],"results":[]},"shard":"shard0"}]"#;
+        let frames = [event, empty, event, event].map(str::to_owned);
+
+        // ticks without output are skipped rather than counted, and events
+        // without output don't look the game time up
+        assert_eq!(
+            output(&frames, &[Some(100), Some(105), None]),
+            ["[shard0 100] log", "[shard0 105] log", "[shard0] log"]
+        );
+    }
+}
@@ -1,11 +1,21 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
 };
 
 use log::*;
 
+use crate::{
+    bundle,
+    progress::{self, Event},
+    setup::DeployOptions,
+    size, upload,
+};
+
+/// Copies the `.js` and `.wasm` files in `include_files` into the `branch`
+/// directory of `destination`, or with `options.dry_run`, reports what it would
+/// write and remove without touching anything.
 pub fn copy<P: AsRef<Path>>(
     root: P,
     build_path: &Option<PathBuf>,
@@ -13,17 +23,16 @@ pub fn copy<P: AsRef<Path>>(
     branch: &String,
     include_files: &Vec<PathBuf>,
     prune: bool,
+    options: &DeployOptions,
 ) -> Result<(), anyhow::Error> {
     let root = root.as_ref();
 
-    // join root here so relative directories are correct even if 'cargo screeps' is
-    // run in sub-directory.
-    let output_dir = root.join(destination).join(branch);
-
-    fs::create_dir_all(&output_dir)?;
-
-    let mut deployed: HashSet<PathBuf> = HashSet::new();
+    // catch code which would be too large for the official server, even when
+    // deploying to a private server which doesn't enforce the limit
+    let modules = upload::collect_modules(root, build_path, include_files)?;
+    size::check_limit(&modules, options.allows_oversize())?;
 
+    let mut files = Vec::new();
     for target in include_files {
         let target_dir = build_path
             .as_ref()
@@ -41,25 +50,120 @@ pub fn copy<P: AsRef<Path>>(
                     || extension == "js"
                     || extension == "mjs"
                 {
-                    let output_path = output_dir.join(name);
-                    fs::copy(&path, &output_path)?;
-                    deployed.insert(output_path);
+                    files.push((PathBuf::from(name), fs::read(&path)?));
                 }
             }
         }
     }
 
-    if prune {
-        for entry in fs::read_dir(output_dir)? {
-            let entry = entry?;
-            let path = entry.path();
+    write_files(root, destination, branch, files, prune, options)
+}
 
+/// Copies modules, as read by [`upload::collect_modules`] or from a bundle,
+/// into the `branch` directory of `destination` as `.js` and `.wasm` files, the
+/// same way as [`copy`].
+pub(crate) fn copy_modules(
+    root: &Path,
+    modules: &HashMap<String, serde_json::Value>,
+    destination: &PathBuf,
+    branch: &String,
+    prune: bool,
+    options: &DeployOptions,
+) -> Result<(), anyhow::Error> {
+    size::check_limit(modules, options.allows_oversize())?;
+
+    let mut files = Vec::new();
+    for (name, contents) in modules {
+        let (file_name, data) = bundle::module_file(name, contents)?;
+        files.push((PathBuf::from(file_name), data));
+    }
+
+    write_files(root, destination, branch, files, prune, options)
+}
+
+/// Writes files, given by file name and contents, into the `branch` directory
+/// of `destination`.
+fn write_files(
+    root: &Path,
+    destination: &PathBuf,
+    branch: &String,
+    files: Vec<(PathBuf, Vec<u8>)>,
+    prune: bool,
+    options: &DeployOptions,
+) -> Result<(), anyhow::Error> {
+    // join root here so relative directories are correct even if 'cargo screeps' is
+    // run in sub-directory.
+    let output_dir = root.join(destination).join(branch);
+    let files: Vec<(PathBuf, Vec<u8>)> = files
+        .into_iter()
+        .map(|(name, data)| (output_dir.join(name), data))
+        .collect();
+
+    let deployed: HashSet<&PathBuf> = files.iter().map(|(output_path, _)| output_path).collect();
+    let mut pruned = Vec::new();
+    if prune && output_dir.is_dir() {
+        for entry in fs::read_dir(&output_dir)? {
+            let path = entry?.path();
             if !deployed.contains(&path) {
-                info!("pruning: removing {}", path.display());
-                fs::remove_file(path)?;
+                pruned.push(path);
             }
         }
+        pruned.sort();
+    }
+
+    if options.dry_run {
+        let mut written = Vec::new();
+        let mut unchanged = Vec::new();
+        for (output_path, data) in &files {
+            if !options.force && is_unchanged(data, output_path)? {
+                unchanged.push(output_path.clone());
+            } else {
+                written.push(output_path.clone());
+            }
+        }
+        written.sort();
+        unchanged.sort();
+        progress::emit(Event::CopyPlanned {
+            destination: output_dir,
+            written,
+            unchanged,
+            pruned,
+        });
+        return Ok(());
+    }
+
+    progress::emit(Event::CopyStarted {
+        destination: output_dir.clone(),
+    });
+
+    fs::create_dir_all(&output_dir)?;
+
+    for (output_path, data) in &files {
+        // leave identical files untouched, so the game client doesn't reload
+        // code that hasn't changed
+        if !options.force && is_unchanged(data, output_path)? {
+            debug!("unchanged: {}", output_path.display());
+        } else {
+            fs::write(output_path, data)?;
+        }
+    }
+
+    for path in pruned {
+        fs::remove_file(&path)?;
+        progress::emit(Event::FilePruned { path });
     }
 
+    progress::emit(Event::CopyFinished {
+        destination: output_dir,
+    });
+
     Ok(())
 }
+
+fn is_unchanged(data: &[u8], destination: &Path) -> Result<bool, anyhow::Error> {
+    if !destination.exists() || fs::metadata(destination)?.len() != data.len() as u64 {
+        return Ok(false);
+    }
+
+    Ok(fs::read(destination)? == data)
+}
@@ -0,0 +1,71 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use log::*;
+use sha2::{Digest, Sha256};
+
+/// Record of what was last uploaded to each server and branch, stored in the
+/// project's `target` directory so unchanged code isn't uploaded again.
+#[derive(Debug)]
+pub struct DeployCache {
+    path: PathBuf,
+    hashes: BTreeMap<String, String>,
+}
+
+impl DeployCache {
+    pub fn load(root: &Path) -> Self {
+        let path = root.join("target").join("screeps-deploy-cache.json");
+
+        let hashes = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("ignoring unreadable deploy cache {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+
+        DeployCache { path, hashes }
+    }
+
+    pub fn is_current(&self, key: &str, hash: &str) -> bool {
+        self.hashes.get(key).map(String::as_str) == Some(hash)
+    }
+
+    pub fn record(&mut self, key: String, hash: String) -> Result<(), anyhow::Error> {
+        self.hashes.insert(key, hash);
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.hashes)?)
+            .with_context(|| format!("writing deploy cache {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Builds the cache key for a deploy target; the url identifies the server's
+/// hostname, port and prefix.
+pub fn cache_key(url: &str, branch: &str) -> String {
+    format!("{url}#{branch}")
+}
+
+/// Hashes the modules map sent to the server, independent of map ordering.
+pub fn hash_modules(modules: &HashMap<String, serde_json::Value>) -> String {
+    let mut names: Vec<&String> = modules.keys().collect();
+    names.sort();
+
+    let mut hasher = Sha256::new();
+    for name in names {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update(modules[name].to_string().as_bytes());
+        hasher.update([0]);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
@@ -0,0 +1,54 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context};
+use log::*;
+
+use crate::{
+    bundle,
+    config::{Authentication, ServerConfiguration},
+    upload,
+};
+
+pub fn download(
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    branch: &str,
+    out_dir: &Path,
+) -> Result<(), anyhow::Error> {
+    let url = server.api_url("user/code");
+    let client = upload::client(server)?;
+
+    let response = upload::send_with_retries(server, || {
+        upload::authenticate(client.get(&url), authentication).query(&[("branch", branch)])
+    })?;
+
+    let response_headers = response.headers().clone();
+    let response_json = upload::api_response(response, &format!("downloading branch '{branch}'"))?;
+    upload::log_rate_limit(&response_headers);
+
+    let modules = response_json
+        .get("modules")
+        .and_then(serde_json::Value::as_object)
+        .ok_or_else(|| anyhow!("response from '{}' contained no modules", url))?;
+
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("creating output directory {}", out_dir.display()))?;
+
+    for (name, contents) in modules {
+        let (file_name, data) = bundle::module_file(name, contents)
+            .with_context(|| format!("reading module from '{url}'"))?;
+
+        let path = out_dir.join(file_name);
+        debug!("writing {}", path.display());
+        fs::write(&path, data).with_context(|| format!("writing {}", path.display()))?;
+    }
+
+    info!(
+        "downloaded {} modules from branch '{}' to {}",
+        modules.len(),
+        branch,
+        out_dir.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use reqwest::StatusCode;
+
+use crate::size;
+
+/// The failures callers may want to tell apart, each with a stable
+/// [`code`](Error::code) and process [`exit_code`](Error::exit_code).
+///
+/// Functions still return [`anyhow::Error`], with one of these as the cause or
+/// context of the failure where it applies; find it with
+/// [`Error::find`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The configuration file couldn't be read, or is invalid.
+    InvalidConfig { path: PathBuf },
+    /// A mode named on the command line or in the configuration isn't defined.
+    ModeNotFound { mode: String },
+    /// wasm-pack failed to build the crate.
+    BuildFailed,
+    /// The JS wasm-bindgen generated isn't shaped the way it's expected to be,
+    /// so couldn't be adapted for Screeps.
+    BindgenOutputMismatch { path: PathBuf },
+    /// Files which were expected to be deployed or built don't exist.
+    MissingFiles { path: PathBuf, description: String },
+    /// The files to deploy are over the server's code size limit, or the built
+    /// modules are over the `size_budget` build option.
+    SizeLimitExceeded {
+        total_size: u64,
+        limit: u64,
+        /// The largest modules, with their sizes.
+        largest: Vec<(String, u64)>,
+        /// Whether `limit` is the `size_budget` build option, rather than the
+        /// server's limit.
+        budget: bool,
+    },
+    /// The server didn't accept the credentials.
+    AuthenticationRejected { url: String, message: String },
+    /// The server is rate limiting us, and the limit doesn't reset soon enough
+    /// to wait for it.
+    RateLimited {
+        url: String,
+        resets_in: Option<Duration>,
+    },
+    /// The server responded with an error.
+    Server { url: String, message: String },
+}
+
+impl Error {
+    /// Finds the error of this type among the causes and context of an error.
+    pub fn find(error: &anyhow::Error) -> Option<&Error> {
+        error.downcast_ref()
+    }
+
+    /// The error for a response which wasn't successful.
+    pub(crate) fn from_status(status: StatusCode, url: String, message: String) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Error::AuthenticationRejected { url, message }
+            }
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited {
+                url,
+                resets_in: None,
+            },
+            _ => Error::Server {
+                url,
+                message: format!("{status}: {message}"),
+            },
+        }
+    }
+
+    /// A short name for the kind of error, which won't change between
+    /// versions.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidConfig { .. } => "invalid_config",
+            Error::ModeNotFound { .. } => "mode_not_found",
+            Error::BuildFailed => "build_failed",
+            Error::BindgenOutputMismatch { .. } => "bindgen_output_mismatch",
+            Error::MissingFiles { .. } => "missing_files",
+            Error::SizeLimitExceeded { .. } => "size_limit_exceeded",
+            Error::AuthenticationRejected { .. } => "authentication_rejected",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::Server { .. } => "server_error",
+        }
+    }
+
+    /// The code `cargo screeps` exits with when failing with this error; it
+    /// exits with 1 for other errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::InvalidConfig { .. } => 10,
+            Error::ModeNotFound { .. } => 11,
+            Error::BuildFailed => 20,
+            Error::BindgenOutputMismatch { .. } => 21,
+            Error::MissingFiles { .. } => 22,
+            Error::SizeLimitExceeded { .. } => 30,
+            Error::AuthenticationRejected { .. } => 40,
+            Error::RateLimited { .. } => 41,
+            Error::Server { .. } => 42,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidConfig { path } => {
+                write!(f, "invalid configuration in {}", path.display())
+            }
+            Error::ModeNotFound { mode } => write!(
+                f,
+                "couldn't find mode {mode}, must be defined in screeps.toml"
+            ),
+            Error::BuildFailed => write!(f, "building with wasm-pack failed"),
+            Error::BindgenOutputMismatch { path } => write!(
+                f,
+                "'wasm-pack' generated unexpected JS output! This means it's updated without \
+                 'cargo screeps' also having updated. Please report this issue to \
+                 https://github.com/rustyscreeps/cargo-screeps/issues and include \
+                 the first ~30 lines of {}",
+                path.display(),
+            ),
+            Error::MissingFiles { path, description } => {
+                write!(f, "{}: {}", description, path.display())
+            }
+            Error::SizeLimitExceeded {
+                total_size,
+                limit,
+                largest,
+                budget: true,
+            } => write!(
+                f,
+                "built modules are {} encoded, over the size budget of {}; largest modules: {}. \
+                 run 'cargo screeps size' to see what's taking up space",
+                size::format_size(*total_size),
+                size::format_size(*limit),
+                format_largest(largest),
+            ),
+            Error::SizeLimitExceeded {
+                total_size,
+                limit,
+                largest,
+                budget: false,
+            } => write!(
+                f,
+                "files to deploy are {} ({:.2}% of the {} limit), the server will reject them; \
+                 largest modules: {}. run 'cargo screeps size' to see what's taking up space, or \
+                 pass --allow-oversize to deploy anyway",
+                size::format_size(*total_size),
+                *total_size as f64 / *limit as f64 * 100.,
+                size::format_size(*limit),
+                format_largest(largest),
+            ),
+            Error::AuthenticationRejected { url, message } => {
+                write!(f, "'{url}' rejected the credentials: {message}")
+            }
+            Error::RateLimited {
+                url,
+                resets_in: Some(resets_in),
+            } => write!(
+                f,
+                "rate limited by '{}', and the limit doesn't reset for another {}s; giving up",
+                url,
+                resets_in.as_secs(),
+            ),
+            Error::RateLimited {
+                url,
+                resets_in: None,
+            } => write!(f, "rate limited by '{url}'"),
+            Error::Server { url, message } => write!(f, "error from '{url}': {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Lists the largest modules for size errors, with their sizes.
+pub(crate) fn format_largest(largest: &[(String, u64)]) -> String {
+    largest
+        .iter()
+        .map(|(name, size)| format!("{} ({})", name, size::format_size(*size)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
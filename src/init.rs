@@ -0,0 +1,313 @@
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use log::*;
+
+use crate::{config::BuildMode, orientation, setup::CliConfig};
+
+const DEFAULT_CONFIG: &str = include_str!("../screeps-defaults.toml");
+
+/// Sets up the crate in the current directory for building with cargo-screeps:
+/// writes `screeps.toml` and a javascript loader, and makes sure the crate
+/// builds a `cdylib`.
+pub fn init(cli_config: &CliConfig) -> Result<(), anyhow::Error> {
+    let options = &cli_config.init_options;
+    let root = orientation::find_crate_root()?;
+    let config_path = cli_config
+        .config_path
+        .clone()
+        .unwrap_or_else(|| root.join("screeps.toml"));
+    let loader_path = root.join("javascript").join("main.js");
+    let build_mode = options.build_mode.clone().unwrap_or(BuildMode::World);
+    let interactive = io::stdin().is_terminal();
+
+    // check everything before writing anything, so a refusal leaves no trace
+    if !options.force {
+        for path in [&config_path, &loader_path] {
+            ensure!(
+                !path.exists(),
+                "{} already exists, pass --force to overwrite it",
+                path.display()
+            );
+        }
+    }
+
+    let server = match &options.server {
+        Some(server) => Some(server.clone()),
+        None if interactive && matches!(build_mode, BuildMode::World) => {
+            prompt("server to upload to (leave empty for screeps.com)")?
+        }
+        None => None,
+    };
+    let token = match &options.token {
+        Some(token) => Some(token.clone()),
+        None if interactive && matches!(build_mode, BuildMode::World) => {
+            prompt("auth token (leave empty to fill in later)")?
+        }
+        None => None,
+    };
+
+    let config = default_config(&build_mode, server.as_deref(), token.as_deref())?;
+    fs::write(&config_path, config)
+        .with_context(|| format!("writing {}", config_path.display()))?;
+    info!("wrote {}", config_path.display());
+
+    check_crate_type(&root.join("Cargo.toml"), interactive)?;
+
+    match build_mode {
+        BuildMode::World => {
+            let out_name = root
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .ok_or_else(|| anyhow!("crate directory {} has no name", root.display()))?;
+            fs::create_dir_all(root.join("javascript"))?;
+            fs::write(&loader_path, world_loader(&out_name))
+                .with_context(|| format!("writing {}", loader_path.display()))?;
+            info!("wrote {}", loader_path.display());
+        }
+        BuildMode::Arena => {
+            info!("not writing a javascript loader, arena bots import the built module directly");
+        }
+    }
+
+    // screeps.toml holds the auth token, and screeps.local.toml any credentials
+    // moved out of it
+    add_to_gitignore(
+        &root.join(".gitignore"),
+        &["/screeps.toml", "/screeps.local.toml"],
+    )?;
+
+    info!("initialized, run 'cargo screeps deploy' to build and deploy");
+
+    Ok(())
+}
+
+/// The default configuration, adjusted for the options given.
+fn default_config(
+    build_mode: &BuildMode,
+    server: Option<&str>,
+    token: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    let mut config = DEFAULT_CONFIG.to_owned();
+
+    if let BuildMode::Arena = build_mode {
+        config = replace_line(&config, "# [build]", "[build]")?;
+        config = replace_line(
+            &config,
+            "# build_mode = \"world\"",
+            "build_mode = \"arena\"",
+        )?;
+        // there's no javascript loader to include
+        config = replace_line(
+            &config,
+            "# include_files = [\"pkg\", \"javascript\"]",
+            "include_files = [\"pkg\"]",
+        )?;
+    }
+
+    if let Some(token) = token {
+        config = replace_line(
+            &config,
+            "auth_token = \"your auth token\"",
+            &format!("auth_token = {}", toml::Value::from(token)),
+        )?;
+    }
+
+    if let Some(server) = server {
+        let (hostname, ssl, port) = parse_server(server)?;
+        config = replace_line(
+            &config,
+            "# hostname = \"screeps.com\"",
+            &format!("hostname = {}", toml::Value::from(hostname)),
+        )?;
+        config = replace_line(&config, "# ssl = true", &format!("ssl = {ssl}"))?;
+        config = replace_line(&config, "# port = 443", &format!("port = {port}"))?;
+    }
+
+    Ok(config)
+}
+
+/// Replaces the first line of the default configuration which is exactly
+/// `from`.
+fn replace_line(config: &str, from: &str, to: &str) -> Result<String, anyhow::Error> {
+    let mut replaced = false;
+    let lines: Vec<&str> = config
+        .lines()
+        .map(|line| {
+            if !replaced && line == from {
+                replaced = true;
+                to
+            } else {
+                line
+            }
+        })
+        .collect();
+    ensure!(
+        replaced,
+        "expected default configuration to contain the line '{}'",
+        from
+    );
+
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Reads a server given as a hostname, or as a URL like
+/// `http://localhost:21025`.
+fn parse_server(server: &str) -> Result<(String, bool, u16), anyhow::Error> {
+    if !server.contains("://") {
+        return Ok((server.to_owned(), true, 443));
+    }
+
+    let url = reqwest::Url::parse(server).with_context(|| format!("parsing server '{server}'"))?;
+    let ssl = match url.scheme() {
+        "https" => true,
+        "http" => false,
+        other => bail!("server '{}' has unsupported scheme '{}'", server, other),
+    };
+    let hostname = url
+        .host_str()
+        .ok_or_else(|| anyhow!("server '{}' has no hostname", server))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("server '{}' has no port", server))?;
+
+    Ok((hostname.to_owned(), ssl, port))
+}
+
+/// Makes sure the crate builds a `cdylib`, which wasm-pack needs, offering to
+/// add it when running interactively.
+fn check_crate_type(cargo_toml_path: &Path, interactive: bool) -> Result<(), anyhow::Error> {
+    let contents = fs::read_to_string(cargo_toml_path)
+        .with_context(|| format!("reading {}", cargo_toml_path.display()))?;
+    let mut manifest: toml_edit::DocumentMut = contents
+        .parse()
+        .with_context(|| format!("parsing {}", cargo_toml_path.display()))?;
+
+    let crate_types = manifest
+        .get("lib")
+        .and_then(|lib| lib.get("crate-type"))
+        .and_then(|crate_types| crate_types.as_array());
+    if crate_types.is_some_and(|types| types.iter().any(|t| t.as_str() == Some("cdylib"))) {
+        return Ok(());
+    }
+
+    let question = format!(
+        "{} doesn't set crate-type = [\"cdylib\"], which is needed to build a wasm module; add it?",
+        cargo_toml_path.display()
+    );
+    if !interactive || !confirm(&question)? {
+        warn!(
+            "not changing {}; add crate-type = [\"cdylib\"] to its [lib] section before building",
+            cargo_toml_path.display()
+        );
+        return Ok(());
+    }
+
+    let lib = manifest
+        .entry("lib")
+        .or_insert_with(toml_edit::table)
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow!("[lib] in {} isn't a table", cargo_toml_path.display()))?;
+    match lib
+        .get_mut("crate-type")
+        .and_then(|crate_types| crate_types.as_array_mut())
+    {
+        Some(crate_types) => crate_types.push("cdylib"),
+        None => {
+            lib.insert(
+                "crate-type",
+                toml_edit::value(toml_edit::Array::from_iter(["cdylib"])),
+            );
+        }
+    }
+
+    fs::write(cargo_toml_path, manifest.to_string())
+        .with_context(|| format!("writing {}", cargo_toml_path.display()))?;
+    info!("added cdylib crate type to {}", cargo_toml_path.display());
+
+    Ok(())
+}
+
+fn world_loader(out_name: &str) -> String {
+    format!(
+        r#""use strict";
+
+// the wasm module is loaded on the first tick, and again whenever the global
+// environment is reset
+let bot;
+
+module.exports.loop = function () {{
+    if (!bot) {{
+        bot = require("{out_name}");
+        bot.initialize_instance();
+    }}
+
+    // exported from Rust with `#[wasm_bindgen(js_name = loop)]`
+    bot.loop();
+}};
+"#
+    )
+}
+
+fn add_to_gitignore(gitignore_path: &Path, entries: &[&str]) -> Result<(), anyhow::Error> {
+    let existing = match fs::read_to_string(gitignore_path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(format!("reading {}", gitignore_path.display())),
+    };
+
+    let missing: Vec<&str> = entries
+        .iter()
+        .copied()
+        .filter(|entry| !existing.lines().any(|line| line.trim() == *entry))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let mut contents = existing;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    for entry in &missing {
+        contents.push_str(entry);
+        contents.push('\n');
+    }
+    fs::write(gitignore_path, contents)
+        .with_context(|| format!("writing {}", gitignore_path.display()))?;
+    info!(
+        "added {} to {}",
+        missing.join(", "),
+        gitignore_path.display()
+    );
+
+    Ok(())
+}
+
+/// Asks for a value on the terminal, returning `None` if left empty.
+///
+/// Asks on stderr, leaving stdout to the events printed with `--message-format
+/// json`.
+fn prompt(question: &str) -> Result<Option<String>, anyhow::Error> {
+    eprint!("{question}: ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+
+    Ok(Some(answer.to_owned()).filter(|answer| !answer.is_empty()))
+}
+
+fn confirm(question: &str) -> Result<bool, anyhow::Error> {
+    let answer = prompt(&format!("{question} [Y/n]"))?;
+
+    Ok(matches!(
+        answer.as_deref().map(str::to_lowercase).as_deref(),
+        None | Some("y" | "yes")
+    ))
+}
@@ -0,0 +1,38 @@
+//! Builds Rust crates to wasm and deploys them to Screeps servers.
+//!
+//! This is the library behind `cargo screeps`, for tools which build or deploy
+//! without shelling out to it. [`Project`] reads a crate's `screeps.toml` and
+//! runs its modes; the [`build`], [`upload`], [`copy`] and [`size`] modules
+//! have the individual steps. Progress is logged through the `log` crate, and
+//! passed to the listener set with [`progress::set_listener`].
+
+mod artifact;
+mod branch;
+pub mod build;
+mod build_info;
+mod bundle;
+mod check;
+pub mod config;
+mod console;
+pub mod copy;
+mod deploy_cache;
+mod download;
+mod error;
+mod init;
+mod orientation;
+pub mod progress;
+mod project;
+pub mod redact;
+mod run;
+mod setup;
+pub mod size;
+pub mod symbols;
+pub mod upload;
+mod watch;
+
+pub use crate::{
+    error::Error,
+    project::Project,
+    run::run,
+    setup::{ActiveBranch, DeployOptions},
+};
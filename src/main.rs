@@ -1,14 +1,11 @@
-mod build;
-mod config;
-mod copy;
-mod orientation;
-mod run;
-mod setup;
-mod upload;
-
 fn main() {
-    if let Err(e) = run::run() {
-        eprintln!("error: {:?}", e);
-        std::process::exit(1);
+    if let Err(e) = cargo_screeps::run() {
+        // errors can quote the configuration, credentials included
+        eprintln!(
+            "error: {}",
+            cargo_screeps::redact::redact(&format!("{:?}", e))
+        );
+        let exit_code = cargo_screeps::Error::find(&e).map_or(1, |e| e.exit_code());
+        std::process::exit(exit_code);
     }
 }
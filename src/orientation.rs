@@ -36,9 +36,9 @@ pub fn find_project_root(cli_config: &CliConfig) -> Result<PathBuf, anyhow::Erro
         search_dir(&canon_here).ok_or_else(|| {
             anyhow!(
                 "could not find 'screeps.toml' in {} or parents.\n\
-                 Please create 'screeps.toml' in project root. (example at \
-                 https://github.com/rustyscreeps/cargo-screeps/\
-                 blob/master/screeps-defaults.toml)",
+                 Please create 'screeps.toml' in project root, by running 'cargo screeps init' \
+                 or copying the example at https://github.com/rustyscreeps/cargo-screeps/\
+                 blob/master/screeps-defaults.toml",
                 canon_here.display()
             )
         })
@@ -58,3 +58,23 @@ fn search_dir(dir: &Path) -> Option<PathBuf> {
         }
     }
 }
+
+/// Finds the crate the current directory is in, for commands which run before
+/// there's a `screeps.toml`.
+pub fn find_crate_root() -> Result<PathBuf, anyhow::Error> {
+    let here = env::current_dir()?;
+    let mut current = here.clone();
+
+    loop {
+        if current.join("Cargo.toml").exists() {
+            return Ok(current);
+        }
+        if !current.pop() {
+            return Err(anyhow!(
+                "could not find 'Cargo.toml' in {} or parents; create a crate with \
+                 'cargo new --lib' first",
+                here.display()
+            ));
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use log::*;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    error::Error,
+    redact,
+    size::{self, ModuleSize},
+};
+
+/// Something that happened while building or deploying, passed to the listener
+/// set with [`set_listener`] as well as logged.
+///
+/// Serializes to JSON as an object with the kind of event as its `reason`, as
+/// printed with `--message-format json`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Event {
+    BuildStarted,
+    /// The build finished, leaving `artifacts` in the output directory.
+    BuildFinished {
+        artifacts: Vec<PathBuf>,
+    },
+    /// `wasm-opt` finished optimizing a wasm file.
+    WasmOptimized {
+        path: PathBuf,
+        size_before: u64,
+        size_after: u64,
+    },
+    /// The modules about to be deployed were measured against the code size
+    /// limit.
+    SizeChecked {
+        total_size: u64,
+        limit: u64,
+        /// The modules to deploy, largest first.
+        modules: Vec<ModuleSize>,
+    },
+    CopyStarted {
+        destination: PathBuf,
+    },
+    /// A file in the copy destination which isn't part of the build was removed.
+    FilePruned {
+        path: PathBuf,
+    },
+    CopyFinished {
+        destination: PathBuf,
+    },
+    /// A dry run found the files a copy would write, leave alone because
+    /// they're unchanged, and prune, without touching any of them.
+    CopyPlanned {
+        destination: PathBuf,
+        written: Vec<PathBuf>,
+        unchanged: Vec<PathBuf>,
+        pruned: Vec<PathBuf>,
+    },
+    UploadStarted {
+        url: String,
+        branch: String,
+    },
+    /// The files were identical to the last upload to the same branch, so
+    /// weren't uploaded again.
+    UploadSkipped {
+        url: String,
+        branch: String,
+    },
+    UploadFinished {
+        url: String,
+        branch: String,
+        status: u16,
+    },
+    /// A dry run found the modules an upload would send, without sending them;
+    /// `unchanged` if it would be skipped since they're identical to the last
+    /// upload.
+    UploadPlanned {
+        url: String,
+        branch: String,
+        total_size: u64,
+        /// The modules to upload, largest first.
+        modules: Vec<ModuleSize>,
+        unchanged: bool,
+    },
+    /// `cargo screeps package` wrote a bundle of the modules to deploy.
+    BundleWritten {
+        path: PathBuf,
+        /// The hash of all the modules together, as listed in the bundle's
+        /// manifest.
+        modules_hash: String,
+    },
+    /// A request failed, and will be retried after `delay`.
+    Retrying {
+        url: String,
+        #[serde(rename = "error")]
+        reason: String,
+        #[serde(rename = "delay_secs", serialize_with = "serialize_secs")]
+        delay: Duration,
+        attempt: u32,
+        max_attempts: u32,
+    },
+    /// The server reported how many more requests it'll accept before rate
+    /// limiting us.
+    RateLimit {
+        remaining: u64,
+        #[serde(rename = "resets_in_secs", serialize_with = "serialize_opt_secs")]
+        resets_in: Option<Duration>,
+    },
+    /// One of several modes deployed together finished deploying, or failed to.
+    ModeFinished {
+        mode: String,
+        error: Option<String>,
+        /// The [`Error::code`] of the error, if it's one of the typed errors.
+        code: Option<&'static str>,
+    },
+}
+
+impl Event {
+    /// The level the event is logged at.
+    pub fn level(&self) -> Level {
+        match self {
+            Event::SizeChecked {
+                total_size, limit, ..
+            } if *total_size as f64 > *limit as f64 * 0.9 => Level::Warn,
+            Event::Retrying { .. } => Level::Warn,
+            Event::ModeFinished { error: Some(_), .. } => Level::Error,
+            _ => Level::Info,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::BuildStarted => write!(f, "compiling..."),
+            Event::BuildFinished { .. } => write!(f, "compiled."),
+            Event::WasmOptimized {
+                path,
+                size_before,
+                size_after,
+            } => write!(
+                f,
+                "wasm-opt: {} from {} to {} ({:+.1}%)",
+                path.display(),
+                size::format_size(*size_before),
+                size::format_size(*size_after),
+                (*size_after as f64 - *size_before as f64) / *size_before as f64 * 100.,
+            ),
+            Event::SizeChecked {
+                total_size, limit, ..
+            } => {
+                let pct_consumed = *total_size as f64 / *limit as f64 * 100.;
+                let prefix = if total_size > limit {
+                    "Files to deploy over limit, failure expected!"
+                } else if pct_consumed > 90. {
+                    "Files to deploy near limit!"
+                } else {
+                    "Files to deploy consuming"
+                };
+                write!(
+                    f,
+                    "{} {} of {} limit ({:.2}%)",
+                    prefix,
+                    size::format_size(*total_size),
+                    size::format_size(*limit),
+                    pct_consumed,
+                )
+            }
+            Event::CopyStarted { .. } => write!(f, "copying..."),
+            Event::FilePruned { path } => write!(f, "pruning: removing {}", path.display()),
+            Event::CopyFinished { .. } => write!(f, "copied."),
+            Event::CopyPlanned {
+                destination,
+                written,
+                unchanged,
+                pruned,
+            } => {
+                write!(
+                    f,
+                    "dry run: would copy {} files to {} ({} unchanged) and prune {}",
+                    written.len(),
+                    destination.display(),
+                    unchanged.len(),
+                    pruned.len(),
+                )?;
+                for path in written {
+                    write!(f, "\n  write: {}", path.display())?;
+                }
+                for path in unchanged {
+                    write!(f, "\n  unchanged: {}", path.display())?;
+                }
+                for path in pruned {
+                    write!(f, "\n  remove: {}", path.display())?;
+                }
+                Ok(())
+            }
+            Event::UploadStarted { .. } => write!(f, "uploading..."),
+            Event::UploadSkipped { url, branch } => write!(
+                f,
+                "files unchanged since last upload to branch '{branch}' of '{url}', skipping \
+                 (use --force to upload anyway)"
+            ),
+            Event::UploadFinished { .. } => write!(f, "uploaded."),
+            Event::UploadPlanned {
+                url,
+                branch,
+                total_size,
+                modules,
+                unchanged,
+            } => {
+                write!(
+                    f,
+                    "dry run: would upload {} modules ({}) to branch '{}' of '{}'",
+                    modules.len(),
+                    size::format_size(*total_size),
+                    branch,
+                    url,
+                )?;
+                if *unchanged {
+                    write!(
+                        f,
+                        ", but they're unchanged since the last upload so it'd be skipped \
+                         (use --force to upload anyway)"
+                    )?;
+                }
+                for module in modules {
+                    write!(
+                        f,
+                        "\n  {} ({})",
+                        module.name,
+                        size::format_size(module.size)
+                    )?;
+                }
+                Ok(())
+            }
+            Event::BundleWritten { path, modules_hash } => write!(
+                f,
+                "packaged modules into {} (modules hash {})",
+                path.display(),
+                modules_hash
+            ),
+            Event::Retrying {
+                url,
+                reason,
+                delay,
+                attempt,
+                max_attempts,
+            } => write!(
+                f,
+                "request to '{}' failed with {}, retrying in {}s (attempt {}/{})",
+                url,
+                reason,
+                delay.as_secs(),
+                attempt,
+                max_attempts,
+            ),
+            Event::RateLimit {
+                remaining,
+                resets_in: Some(resets_in),
+            } => write!(
+                f,
+                "{} requests to this endpoint remaining; limit resets in {}m",
+                remaining,
+                resets_in.as_secs() / 60,
+            ),
+            Event::RateLimit {
+                remaining,
+                resets_in: None,
+            } => write!(f, "{remaining} requests to this endpoint remaining"),
+            Event::ModeFinished {
+                mode, error: None, ..
+            } => write!(f, "deployed mode {mode}"),
+            Event::ModeFinished {
+                mode,
+                error: Some(error),
+                ..
+            } => write!(f, "deploying mode {mode} failed: {error}"),
+        }
+    }
+}
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+fn serialize_opt_secs<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration
+        .map(|duration| duration.as_secs())
+        .serialize(serializer)
+}
+
+type Listener = Arc<dyn Fn(&Event) + Send + Sync>;
+
+static LISTENER: RwLock<Option<Listener>> = RwLock::new(None);
+
+/// Sets a function to be called with each event as it happens, replacing any
+/// listener set before.
+pub fn set_listener(listener: impl Fn(&Event) + Send + Sync + 'static) {
+    *LISTENER.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(listener));
+}
+
+/// Removes the listener, leaving events only logged.
+pub fn clear_listener() {
+    *LISTENER.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Logs an event and passes it to the listener.
+pub(crate) fn emit(event: Event) {
+    log!(event.level(), "{}", event);
+
+    let listener = LISTENER.read().unwrap_or_else(|e| e.into_inner()).clone();
+    if let Some(listener) = listener {
+        listener(&event);
+    }
+}
+
+/// Prints an event to stdout as a line of JSON, with credentials masked like in
+/// log output.
+pub(crate) fn print_json(event: &impl Serialize) {
+    match serde_json::to_string(event) {
+        Ok(line) => println!("{}", redact::redact(&line)),
+        Err(e) => warn!("couldn't serialize event: {}", e),
+    }
+}
+
+/// Prints the error a command failed with to stdout as a line of JSON, with the
+/// code of its typed [`Error`] if it has one.
+pub(crate) fn print_json_error(error: &anyhow::Error) {
+    print_json(&serde_json::json!({
+        "reason": "error",
+        "code": Error::find(error).map(Error::code),
+        "message": format!("{error:#}"),
+    }));
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    build,
+    config::{Configuration, CopyConfiguration, ModeConfiguration, UploadConfiguration},
+    copy,
+    setup::DeployOptions,
+    upload,
+};
+
+/// A crate set up for building and deploying with cargo-screeps, and its
+/// configuration.
+#[derive(Clone, Debug)]
+pub struct Project {
+    root: PathBuf,
+    config: Configuration,
+}
+
+impl Project {
+    /// Reads the configuration of the crate in `root` from its `screeps.toml`.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, anyhow::Error> {
+        let root = root.into();
+        let config_path = root.join("screeps.toml");
+        Project::open_with_config(root, &config_path, false)
+    }
+
+    /// Reads the configuration of the crate in `root` from `config_path`, with
+    /// `strict` making unused configuration keys an error.
+    pub fn open_with_config(
+        root: impl Into<PathBuf>,
+        config_path: &Path,
+        strict: bool,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Project {
+            root: root.into(),
+            config: Configuration::read(config_path, strict)?,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn config(&self) -> &Configuration {
+        &self.config
+    }
+
+    /// Looks up a mode (or the default mode, if `name` is `None`), returning its
+    /// name and options with the global build section merged in.
+    pub fn mode(&self, name: Option<&str>) -> Result<(String, ModeConfiguration), anyhow::Error> {
+        let name = self.config.mode_name(name)?;
+        let mode = self.config.mode(&name)?;
+
+        Ok((name, mode))
+    }
+
+    /// Builds the crate with a mode's build options.
+    pub fn build(&self, mode: &ModeConfiguration) -> Result<(), anyhow::Error> {
+        build::build(&self.root, mode.build())
+    }
+
+    /// Reads the modules a mode would deploy from the last build, by name.
+    pub fn collect_modules(
+        &self,
+        mode: &ModeConfiguration,
+    ) -> Result<HashMap<String, serde_json::Value>, anyhow::Error> {
+        upload::collect_modules(&self.root, &mode.build().path, mode.include_files())
+    }
+
+    /// Copies or uploads the files from the last build, depending on the kind
+    /// of mode.
+    pub fn deploy_built(
+        &self,
+        mode: &ModeConfiguration,
+        options: &DeployOptions,
+    ) -> Result<(), anyhow::Error> {
+        deploy_built(&self.root, mode, options)
+    }
+
+    /// Builds the crate and deploys it with a mode.
+    pub fn deploy(
+        &self,
+        mode: &ModeConfiguration,
+        options: &DeployOptions,
+    ) -> Result<(), anyhow::Error> {
+        self.build(mode)?;
+        self.deploy_built(mode, options)
+    }
+}
+
+/// Copies or uploads the files from the last build of the crate at `root`.
+pub(crate) fn deploy_built(
+    root: &Path,
+    mode: &ModeConfiguration,
+    options: &DeployOptions,
+) -> Result<(), anyhow::Error> {
+    match mode {
+        ModeConfiguration::Copy(CopyConfiguration {
+            destination,
+            branch,
+            build,
+            include_files,
+            prune,
+        }) => copy::copy(
+            root,
+            &build.path,
+            destination,
+            branch,
+            include_files,
+            *prune,
+            options,
+        ),
+        ModeConfiguration::Upload(UploadConfiguration {
+            authentication,
+            branch,
+            build,
+            include_files,
+            server,
+        }) => {
+            let authentication = authentication.resolve().context("reading credentials")?;
+            upload::upload(
+                root,
+                &build.path,
+                &authentication,
+                server,
+                branch,
+                include_files,
+                options,
+            )
+        }
+    }
+}
+
+/// Copies or uploads modules, as read from a bundle, with a mode.
+pub(crate) fn deploy_modules(
+    root: &Path,
+    mode: &ModeConfiguration,
+    modules: HashMap<String, serde_json::Value>,
+    options: &DeployOptions,
+) -> Result<(), anyhow::Error> {
+    match mode {
+        ModeConfiguration::Copy(CopyConfiguration {
+            destination,
+            branch,
+            prune,
+            ..
+        }) => copy::copy_modules(root, &modules, destination, branch, *prune, options),
+        ModeConfiguration::Upload(UploadConfiguration {
+            authentication,
+            branch,
+            server,
+            ..
+        }) => {
+            let authentication = authentication.resolve().context("reading credentials")?;
+            upload::upload_modules(root, modules, &authentication, server, branch, options)
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    borrow::Cow,
+    sync::{Mutex, OnceLock},
+};
+
+use regex::Regex;
+
+const REDACTED: &str = "<redacted>";
+
+// credentials read from the configuration or received from the server, masked
+// wherever they show up in log output
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Masks a value in all further log output.
+pub fn register_secret(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_owned());
+    }
+}
+
+/// Masks credentials in a log or error message: registered secrets, `X-Token`
+/// and `Authorization` headers, and token and password fields in JSON bodies
+/// and TOML configuration.
+pub fn redact(message: &str) -> Cow<'_, str> {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        [
+            // headers, as written by reqwest/hyper (`"x-token": "..."`) or raw
+            r#"(?i)(x-token"?\s*[:=]\s*"?)[^"\s,}]+"#,
+            r#"(?i)(authorization"?\s*[:=]\s*"?(?:basic|bearer)\s+)[^"\s,}]+"#,
+            // sign in requests and responses
+            r#"(?i)("(?:token|password|auth_token)"\s*:\s*")[^"]*"#,
+            // configuration quoted in TOML parse errors
+            r#"(?i)\b((?:auth_token|password)\s*=\s*\\?["'])[^"'\\\n]*"#,
+            // websocket authentication
+            r"(?m)(^auth )\S+",
+        ]
+        .iter()
+        .map(|pattern| Regex::new(pattern).expect("expected pre-set regex to succeed"))
+        .collect()
+    });
+
+    let mut message = Cow::Borrowed(message);
+
+    for secret in SECRETS.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        if message.contains(secret.as_str()) {
+            message = Cow::Owned(message.replace(secret.as_str(), REDACTED));
+        }
+    }
+
+    for pattern in patterns {
+        if pattern.is_match(&message) {
+            message = Cow::Owned(
+                pattern
+                    .replace_all(&message, format!("${{1}}{REDACTED}"))
+                    .into_owned(),
+            );
+        }
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_registered_secrets() {
+        register_secret("registered-secret-value");
+
+        assert_eq!(
+            redact("signed in with registered-secret-value as bot"),
+            "signed in with <redacted> as bot"
+        );
+    }
+
+    #[test]
+    fn ignores_empty_secrets() {
+        register_secret("");
+
+        assert!(matches!(redact("nothing to hide"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn masks_headers() {
+        assert_eq!(
+            redact(r#"headers: {"x-token": "abc123", "content-type": "application/json"}"#),
+            r#"headers: {"x-token": "<redacted>", "content-type": "application/json"}"#
+        );
+        assert_eq!(redact("X-Token: abc123"), "X-Token: <redacted>");
+        assert_eq!(
+            redact(r#"{"authorization": "Basic Ym90Omh1bnRlcjI="}"#),
+            r#"{"authorization": "Basic <redacted>"}"#
+        );
+    }
+
+    #[test]
+    fn masks_json_fields() {
+        assert_eq!(
+            redact(r#"{"email":"bot","password":"hunter2"}"#),
+            r#"{"email":"bot","password":"<redacted>"}"#
+        );
+        assert_eq!(
+            redact(r#"{"ok":1,"token":"abc123"}"#),
+            r#"{"ok":1,"token":"<redacted>"}"#
+        );
+    }
+
+    #[test]
+    fn masks_toml_fields() {
+        assert_eq!(
+            redact("4 | password = \"hunter2\" oops"),
+            "4 | password = \"<redacted>\" oops"
+        );
+        assert_eq!(redact("auth_token='abc123'"), "auth_token='<redacted>'");
+        // as quoted in JSON error messages
+        assert_eq!(
+            redact(r#"{"message":"password = \"hunter2\" oops"}"#),
+            r#"{"message":"password = \"<redacted>\" oops"}"#
+        );
+    }
+
+    #[test]
+    fn masks_websocket_auth() {
+        assert_eq!(redact("sending\nauth abc123"), "sending\nauth <redacted>");
+    }
+
+    #[test]
+    fn leaves_other_messages_alone() {
+        let message = "uploading 2 modules to branch 'default'";
+
+        assert!(matches!(redact(message), Cow::Borrowed(m) if m == message));
+    }
+}
@@ -1,24 +1,67 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, ensure, Context};
 use log::*;
-use merge::Merge;
 
 use crate::{
-    build,
-    config::{self, Authentication, BuildConfiguration, ModeConfiguration},
-    copy, orientation, setup, upload,
+    artifact::{self, Artifact},
+    branch, build,
+    bundle::Bundle,
+    check,
+    config::{
+        self, Authentication, BuildConfiguration, ModeConfiguration, ServerConfiguration,
+        UploadConfiguration,
+    },
+    console, download,
+    error::Error,
+    init, orientation,
+    progress::{self, Event},
+    project, setup, size, symbols, upload, watch,
 };
 
+/// Runs `cargo screeps` with the process's arguments, the way the binary does.
+///
+/// Sets up logging to stdout, or stderr with `--message-format json`, unless a
+/// logger is already set.
 pub fn run() -> Result<(), anyhow::Error> {
     let cli_config = setup::setup_cli()?;
+    let message_format = cli_config.message_format;
+
+    let result = run_command(cli_config);
+    if let (Err(e), setup::MessageFormat::Json) = (&result, message_format) {
+        progress::print_json_error(e);
+    }
+
+    result
+}
+
+fn run_command(cli_config: setup::CliConfig) -> Result<(), anyhow::Error> {
+    // there's no configuration to read yet
+    if cli_config.command == setup::Command::Init {
+        return init::init(&cli_config);
+    }
 
     let root = orientation::find_project_root(&cli_config)?;
     let config_path = cli_config
         .config_path
         .unwrap_or_else(|| root.join("screeps.toml"));
 
-    let mut config = config::Configuration::read(&config_path)?;
+    // checking reports on configuration which can't be read, so reads it itself
+    if cli_config.command == setup::Command::Check {
+        return check::check(
+            &root,
+            &config_path,
+            cli_config.strict,
+            cli_config.message_format,
+        );
+    }
+
+    let config = config::Configuration::read(&config_path, cli_config.strict)?;
 
     debug!(
         "Running {:?} at {:?} using config {:?} with values {:#?}",
@@ -26,125 +69,508 @@ pub fn run() -> Result<(), anyhow::Error> {
     );
 
     match cli_config.command {
-        setup::Command::Build => run_build(&root, &config.build)?,
+        setup::Command::Build => {
+            build::build(&root, &config.build)?;
+            let modules = upload::collect_modules(&root, &config.build.path, &vec!["pkg".into()])?;
+            let report =
+                size::SizeReport::new(&modules, &build::output_dir(&root, &config.build), 10)?;
+            match cli_config.message_format {
+                setup::MessageFormat::Human => report.print(),
+                setup::MessageFormat::Json => print_json_report(&report)?,
+            }
+        }
         setup::Command::Deploy => {
-            let mode = match cli_config.deploy_mode {
-                Some(v) => v,
-                None => {
-                    config.default_deploy_mode.ok_or_else(|| {
-                        anyhow!("must have default_deploy_mode set to use 'cargo screeps deploy' without --mode")
-                    })?
-                }
+            let modes = if cli_config.all_modes {
+                let mut modes: Vec<String> = config.modes.keys().cloned().collect();
+                modes.sort();
+                modes
+            } else {
+                cli_config.deploy_modes
             };
-            let target_config = config.modes.remove(&mode).ok_or_else(|| {
-                anyhow!(
-                    "couldn't find mode {}, must be defined in screeps.toml",
-                    mode
-                )
-            })?;
-            match target_config {
-                ModeConfiguration::Copy {
-                    destination,
-                    branch,
-                    mut build,
-                    include_files,
-                    prune,
-                } => {
-                    build.merge(config.build);
-                    run_build(&root, &build)?;
-                    run_copy(
-                        &root,
-                        &build.path,
-                        &destination,
-                        &branch,
-                        &include_files,
-                        prune,
-                    )?;
+            run_deploy(
+                &root,
+                config,
+                modes,
+                &cli_config.deploy_options,
+                cli_config.message_format,
+            )?
+        }
+        setup::Command::Download => {
+            let mode = resolve_deploy_mode(&config, cli_config.deploy_mode)?;
+            let (authentication, server, branch) = resolve_upload_mode(config, &mode)?;
+            let branch = cli_config.branch.unwrap_or(branch);
+            let out_dir = cli_config.out_dir.unwrap_or_else(|| {
+                root.join("target")
+                    .join("screeps-download")
+                    .join(&mode)
+                    .join(&branch)
+            });
+            download::download(&authentication, &server, &branch, &out_dir)?;
+        }
+        setup::Command::Branch => {
+            let mode = resolve_deploy_mode(&config, cli_config.deploy_mode)?;
+            let (authentication, server, mode_branch) = resolve_upload_mode(config, &mode)?;
+            match cli_config
+                .branch_command
+                .expect("expected branch subcommand to be set for branch command")
+            {
+                setup::BranchCommand::List => {
+                    branch::list(&authentication, &server, cli_config.message_format)?
+                }
+                setup::BranchCommand::Clone { branch, new_name } => {
+                    branch::clone(&authentication, &server, &branch, &new_name)?
                 }
-                ModeConfiguration::Upload {
-                    authentication,
-                    branch,
-                    mut build,
-                    include_files,
-                    hostname,
-                    ssl,
-                    port,
-                    prefix,
-                    http_timeout,
-                } => {
-                    let url = format!(
-                        "{}://{}:{}/{}",
-                        if ssl { "https" } else { "http" },
-                        hostname,
-                        port,
-                        match prefix {
-                            Some(prefix) => format!("{prefix}/api/user/code"),
-                            None => "api/user/code".to_string(),
-                        }
-                    );
-
-                    build.merge(config.build);
-                    run_build(&root, &build)?;
-                    run_upload(
-                        &root,
-                        &build.path,
-                        &authentication,
-                        &branch,
-                        &include_files,
-                        &url,
-                        http_timeout,
-                    )?;
+                setup::BranchCommand::Delete { branch } => {
+                    branch::delete(&authentication, &server, &branch)?
+                }
+                setup::BranchCommand::Activate { branch, target } => branch::activate(
+                    &authentication,
+                    &server,
+                    &branch.unwrap_or(mode_branch),
+                    target,
+                )?,
+            }
+        }
+        setup::Command::Console => {
+            let mode = resolve_deploy_mode(&config, cli_config.deploy_mode)?;
+            let (authentication, server, _) = resolve_upload_mode(config, &mode)?;
+            let shard = cli_config.shard.or_else(|| server.shard.clone());
+            console::console(
+                &authentication,
+                &server,
+                shard.as_deref(),
+                cli_config.message_format,
+            )?;
+        }
+        setup::Command::Symbolicate => {
+            let symbols_path = match cli_config.symbols_path {
+                Some(path) => path,
+                None => {
+                    let build = match cli_config.deploy_mode {
+                        Some(mode) => resolve_build_config(config, &mode)?,
+                        None => config.build,
+                    };
+                    find_symbol_map(&build::output_dir(&root, &build))?
                 }
             };
+            let symbol_map = symbols::SymbolMap::read(&symbols_path)?;
+            symbols::symbolicate(io::stdin().lock(), io::stdout().lock(), &symbol_map)?;
+        }
+        setup::Command::Size => {
+            let mode = cli_config
+                .deploy_mode
+                .or_else(|| config.default_deploy_mode.clone());
+            let (build, include_files) = match mode {
+                Some(mode) => resolve_deploy_files(config, &mode)?,
+                None => (config.build, config::default_include_files()),
+            };
+            let modules = upload::collect_modules(&root, &build.path, &include_files)?;
+            let report =
+                size::SizeReport::new(&modules, &build::output_dir(&root, &build), cli_config.top)?;
+            if cli_config.message_format == setup::MessageFormat::Json {
+                print_json_report(&report)?;
+            } else {
+                report.print();
+            }
+        }
+        setup::Command::Package => {
+            let mode = resolve_deploy_mode(&config, cli_config.deploy_mode)?;
+            let mode_config = config.mode(&mode)?;
+            prepare_build(&root, mode_config.build(), &cli_config.deploy_options)?;
+            let out = cli_config.out_file.unwrap_or_else(|| {
+                root.join("target")
+                    .join("screeps-package")
+                    .join(format!("{mode}.json"))
+            });
+            Bundle::package(&root, &mode, &mode_config)?.write(&out)?;
+        }
+        setup::Command::ConfigShow => {
+            let mode = resolve_deploy_mode(&config, cli_config.deploy_mode)?;
+            let mode_config = config.mode(&mode)?;
+            match cli_config.message_format {
+                setup::MessageFormat::Human => {
+                    let shown = HashMap::from([(mode, mode_config)]);
+                    print!("{}", toml::to_string(&shown)?);
+                }
+                setup::MessageFormat::Json => progress::print_json(&serde_json::json!({
+                    "reason": "config",
+                    "mode": mode,
+                    "config": mode_config,
+                })),
+            }
         }
+        setup::Command::Init => unreachable!("init runs before reading configuration"),
+        setup::Command::Check => unreachable!("check runs before reading configuration"),
+        setup::Command::Watch => watch::watch(
+            &root,
+            &config_path,
+            config,
+            cli_config.deploy_mode,
+            cli_config.strict,
+            cli_config.message_format,
+        )?,
     }
 
     Ok(())
 }
 
-fn run_build(root: &Path, config: &BuildConfiguration) -> Result<(), anyhow::Error> {
-    info!("compiling...");
-    build::build(root, config)?;
-    info!("compiled.");
+/// Deploys each of the given modes or groups of modes (or the default mode if
+/// none are given), building once for each distinct build configuration.
+///
+/// When deploying more than one mode, keeps going after a mode fails to build
+/// or deploy, and finishes with a summary of how each mode went.
+pub fn run_deploy(
+    root: &Path,
+    config: config::Configuration,
+    deploy_modes: Vec<String>,
+    options: &setup::DeployOptions,
+    message_format: setup::MessageFormat,
+) -> Result<(), anyhow::Error> {
+    let deploy_modes = if deploy_modes.is_empty() {
+        vec![resolve_deploy_mode(&config, None)?]
+    } else {
+        deploy_modes
+    };
+    let modes = config.expand_groups(&deploy_modes)?;
+    ensure!(
+        !options.follow || modes.len() == 1,
+        "--follow can only be used when deploying a single mode"
+    );
 
-    Ok(())
+    let artifact = match &options.from {
+        Some(from) => Some(artifact::open(root, from)?),
+        None => None,
+    };
+    let bundle = match &artifact {
+        Some(Artifact::Bundle(bundle)) => {
+            info!(
+                "deploying bundle packaged with mode {} from commit {}",
+                bundle.manifest.mode,
+                bundle
+                    .manifest
+                    .build_info
+                    .git_commit
+                    .as_deref()
+                    .unwrap_or("unknown")
+            );
+            Some(bundle.as_ref())
+        }
+        _ => None,
+    };
+
+    // a failed build fails each mode deploying it with the same error
+    let mut results: Vec<(String, Result<(), Rc<anyhow::Error>>)> = Vec::new();
+    let mut finish_mode = |mode: String, result: Result<(), Rc<anyhow::Error>>| {
+        progress::emit(Event::ModeFinished {
+            mode: mode.clone(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+            code: result
+                .as_ref()
+                .err()
+                .and_then(|e| Error::find(e))
+                .map(Error::code),
+        });
+        results.push((mode, result));
+    };
+
+    // modes sharing a build configuration are deployed together after it's
+    // built, before another build replaces the output
+    let single_mode = modes.len() == 1;
+    let mut builds: Vec<(BuildConfiguration, Vec<(String, ModeConfiguration)>)> = Vec::new();
+    for mode in modes.clone() {
+        let target_config = match mode_to_deploy(&config, &mode, artifact.as_ref()) {
+            Ok(target_config) => target_config,
+            Err(e) if single_mode => return Err(e),
+            Err(e) => {
+                finish_mode(mode, Err(Rc::new(e)));
+                continue;
+            }
+        };
+
+        let build = target_config.build().clone();
+        match builds.iter_mut().find(|(existing, _)| *existing == build) {
+            Some((_, targets)) => targets.push((mode, target_config)),
+            None => builds.push((build, vec![(mode, target_config)])),
+        }
+    }
+
+    if let [(build, targets)] = &mut builds[..] {
+        if single_mode {
+            let (mode, target_config) = targets.remove(0);
+            prepare_build(root, build, options)?;
+            return deploy_built(root, &mode, target_config, bundle, options, message_format);
+        }
+    }
+
+    for (build, targets) in builds {
+        let build_result = prepare_build(root, &build, options).map_err(Rc::new);
+        if let Err(e) = &build_result {
+            error!("build failed: {:#}", e);
+        }
+
+        for (mode, target_config) in targets {
+            let result = match &build_result {
+                Ok(()) => deploy_built(root, &mode, target_config, bundle, options, message_format)
+                    .map_err(Rc::new),
+                Err(e) => Err(Rc::clone(e)),
+            };
+            finish_mode(mode, result);
+        }
+    }
+
+    // list modes in the order they were given, rather than that they were
+    // deployed in
+    results.sort_by_key(|(mode, _)| modes.iter().position(|m| m == mode));
+
+    // the mode-finished events cover this in JSON output
+    if message_format == setup::MessageFormat::Human {
+        let mode_width = results
+            .iter()
+            .map(|(mode, _)| mode.len())
+            .max()
+            .unwrap_or(0);
+        println!();
+        for (mode, result) in &results {
+            match result {
+                Ok(()) if options.dry_run => println!("{mode:mode_width$}  ok (dry run)"),
+                Ok(()) => println!("{mode:mode_width$}  deployed"),
+                Err(e) => println!("{mode:mode_width$}  failed: {e:#}"),
+            }
+        }
+    }
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    let total = results.len();
+    // the first failure is the cause, so its kind decides the exit code; other
+    // modes sharing a failed build are dropped with the rest of the results
+    let first_error = results.into_iter().find_map(|(_, result)| result.err());
+    match first_error {
+        Some(e) => {
+            let e = Rc::try_unwrap(e).unwrap_or_else(|e| anyhow!("{e:#}"));
+            Err(e.context(format!("{failed} of {total} modes failed to deploy")))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Reads the configuration of a mode to deploy, checking its credentials
+/// before spending time on a build.
+fn mode_to_deploy(
+    config: &config::Configuration,
+    mode: &str,
+    artifact: Option<&Artifact>,
+) -> Result<ModeConfiguration, anyhow::Error> {
+    let mut target_config = config.mode(mode)?;
+    if let Some(Artifact::BuildOutput(build_output)) = artifact {
+        use_build_output(mode, &mut target_config, build_output)?;
+    }
+
+    if let ModeConfiguration::Upload(upload) = &target_config {
+        upload
+            .authentication
+            .resolve()
+            .with_context(|| format!("reading credentials for mode {mode}"))?;
+    }
+
+    Ok(target_config)
 }
 
-fn run_copy(
+/// Builds the crate for modes to deploy, or with `--no-build` or `--from`,
+/// checks that the build output they'll deploy is there instead.
+fn prepare_build(
     root: &Path,
-    build_path: &Option<PathBuf>,
-    destination: &PathBuf,
-    branch: &String,
-    include_files: &Vec<PathBuf>,
-    prune: bool,
+    build: &BuildConfiguration,
+    options: &setup::DeployOptions,
+) -> Result<(), anyhow::Error> {
+    if options.from.is_some() {
+        // checked when it was opened, along with bundles
+        Ok(())
+    } else if options.no_build {
+        artifact::check(&build::output_dir(root, build))
+            .context("deploying with --no-build, which needs the output of an earlier build")
+    } else {
+        build::build(root, build)
+    }
+}
+
+/// Points a mode at the build output given with `--from`, in place of the `pkg`
+/// directory it would otherwise deploy.
+fn use_build_output(
+    mode: &str,
+    target_config: &mut ModeConfiguration,
+    build_output: &Path,
 ) -> Result<(), anyhow::Error> {
-    info!("copying...");
-    copy::copy(root, build_path, destination, branch, include_files, prune)?;
-    info!("copied.");
+    let mut replaced = false;
+    for include in target_config.include_files_mut() {
+        if include == Path::new("pkg") {
+            // joined onto the build path, an absolute path replaces it
+            *include = build_output.to_owned();
+            replaced = true;
+        }
+    }
+    ensure!(
+        replaced,
+        "mode {} doesn't deploy the build output, as 'pkg' isn't in its include_files, so \
+         can't deploy with --from",
+        mode
+    );
 
     Ok(())
 }
 
-fn run_upload(
+/// Deploys a mode's already-built files, or the modules in a bundle, then
+/// activates the branch or follows the console if asked to.
+fn deploy_built(
     root: &Path,
-    build_path: &Option<PathBuf>,
-    authentication: &Authentication,
-    branch: &String,
-    include_files: &Vec<PathBuf>,
-    url: &String,
-    http_timeout: Option<u32>,
+    mode: &str,
+    target_config: ModeConfiguration,
+    bundle: Option<&Bundle>,
+    options: &setup::DeployOptions,
+    message_format: setup::MessageFormat,
 ) -> Result<(), anyhow::Error> {
-    info!("uploading...");
-    upload::upload(
-        root,
-        build_path,
-        authentication,
-        branch,
-        include_files,
-        url,
-        http_timeout,
-    )?;
-    info!("uploaded.");
+    match bundle {
+        Some(bundle) => project::deploy_modules(root, &target_config, bundle.modules(), options)?,
+        None => project::deploy_built(root, &target_config, options)?,
+    }
+
+    if options.dry_run {
+        if options.activate.is_some() || options.follow {
+            info!("dry run: not activating branch or following console");
+        }
+        return Ok(());
+    }
+
+    match target_config {
+        ModeConfiguration::Copy(_) => {
+            if options.activate.is_some() || options.follow {
+                warn!(
+                    "mode {} copies files rather than uploading them, not activating branch or \
+                     following console",
+                    mode
+                );
+            }
+        }
+        ModeConfiguration::Upload(UploadConfiguration {
+            authentication,
+            branch,
+            server,
+            ..
+        }) => {
+            if options.activate.is_none() && !options.follow {
+                return Ok(());
+            }
+            let authentication = authentication
+                .resolve()
+                .with_context(|| format!("reading credentials for mode {mode}"))?;
+
+            if let Some(target) = options.activate {
+                branch::activate(&authentication, &server, &branch, target)?;
+            }
+
+            if options.follow {
+                let shard = options.shard.as_ref().or(server.shard.as_ref());
+                console::console(
+                    &authentication,
+                    &server,
+                    shard.map(String::as_str),
+                    message_format,
+                )?;
+            }
+        }
+    };
+
+    Ok(())
+}
+
+pub fn resolve_deploy_mode(
+    config: &config::Configuration,
+    deploy_mode: Option<String>,
+) -> Result<String, anyhow::Error> {
+    config.mode_name(deploy_mode.as_deref())
+}
+
+/// Looks up a mode's build configuration, merged with the global build section.
+pub fn resolve_build_config(
+    config: config::Configuration,
+    mode: &str,
+) -> Result<BuildConfiguration, anyhow::Error> {
+    resolve_deploy_files(config, mode).map(|(build, _)| build)
+}
+
+/// Looks up the build configuration of a mode, merged with the global build
+/// section, and the directories it deploys files from.
+pub fn resolve_deploy_files(
+    config: config::Configuration,
+    mode: &str,
+) -> Result<(BuildConfiguration, Vec<PathBuf>), anyhow::Error> {
+    let mode = config.mode(mode)?;
+
+    Ok((mode.build().clone(), mode.include_files().clone()))
+}
+
+/// Prints a size report as a single JSON event.
+fn print_json_report(report: &size::SizeReport) -> Result<(), anyhow::Error> {
+    let mut event = serde_json::json!({ "reason": "size-report" });
+    if let (Some(event), serde_json::Value::Object(report)) =
+        (event.as_object_mut(), serde_json::to_value(report)?)
+    {
+        event.extend(report);
+    }
+    progress::print_json(&event);
 
     Ok(())
 }
+
+fn find_symbol_map(output_dir: &Path) -> Result<PathBuf, anyhow::Error> {
+    let mut symbol_maps = Vec::new();
+    if output_dir.is_dir() {
+        for entry in fs::read_dir(output_dir)? {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with(".symbols.json") {
+                symbol_maps.push(path);
+            }
+        }
+    }
+
+    match symbol_maps.len() {
+        1 => Ok(symbol_maps.remove(0)),
+        0 => Err(anyhow!(
+            "no symbol map found in {}; build with wasm-opt keeping the name section \
+             (the '-g' flag), or pass one with --symbols",
+            output_dir.display()
+        )),
+        _ => Err(anyhow!(
+            "multiple symbol maps found in {}, choose one with --symbols",
+            output_dir.display()
+        )),
+    }
+}
+
+/// Looks up the server connection settings and branch of an upload mode, for
+/// commands which talk to the server without deploying.
+pub fn resolve_upload_mode(
+    mut config: config::Configuration,
+    mode: &str,
+) -> Result<(Authentication, ServerConfiguration, String), anyhow::Error> {
+    match config.modes.remove(mode) {
+        Some(ModeConfiguration::Upload(UploadConfiguration {
+            authentication,
+            server,
+            branch,
+            ..
+        })) => {
+            let authentication = authentication
+                .resolve()
+                .with_context(|| format!("reading credentials for mode {mode}"))?;
+            Ok((authentication, server, branch))
+        }
+        Some(ModeConfiguration::Copy(_)) => Err(anyhow!(
+            "mode {} copies files rather than uploading them, so has no server to connect to",
+            mode
+        )),
+        None => Err(Error::ModeNotFound {
+            mode: mode.to_owned(),
+        }
+        .into()),
+    }
+}
@@ -1,18 +1,111 @@
 use std::{io, path::PathBuf};
 
 use anyhow::anyhow;
+use log::*;
+
+use crate::{config::BuildMode, progress, redact};
 
 #[derive(Clone, Debug)]
 pub struct CliConfig {
     pub command: Command,
     pub config_path: Option<PathBuf>,
     pub deploy_mode: Option<String>,
+    pub deploy_modes: Vec<String>,
+    pub all_modes: bool,
+    pub deploy_options: DeployOptions,
+    pub init_options: InitOptions,
+    pub branch: Option<String>,
+    pub out_dir: Option<PathBuf>,
+    pub out_file: Option<PathBuf>,
+    pub branch_command: Option<BranchCommand>,
+    pub shard: Option<String>,
+    pub symbols_path: Option<PathBuf>,
+    pub top: usize,
+    pub strict: bool,
+    pub message_format: MessageFormat,
+}
+
+/// How progress and results are written out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Log messages on stdout.
+    Human,
+    /// Newline-delimited JSON events on stdout, with log messages moved to
+    /// stderr.
+    Json,
+}
+
+/// Options controlling how a deploy mode is run.
+#[derive(Clone, Debug, Default)]
+pub struct DeployOptions {
+    pub force: bool,
+    pub allow_oversize: bool,
+    pub activate: Option<ActiveBranch>,
+    pub follow: bool,
+    pub shard: Option<String>,
+    /// Report what would be deployed without copying or uploading anything.
+    pub dry_run: bool,
+    /// Deploy the output of the last build rather than building first.
+    pub no_build: bool,
+    /// Deploy the build output in this directory or `.tar.gz` archive, in
+    /// place of the `pkg` directory, rather than building first.
+    pub from: Option<PathBuf>,
+}
+
+impl DeployOptions {
+    /// Whether code over the size limit is deployed rather than failing; dry
+    /// runs only warn about it, so they still list what would be deployed.
+    pub(crate) fn allows_oversize(&self) -> bool {
+        self.allow_oversize || self.dry_run
+    }
+}
+
+/// Options for setting up a new project.
+#[derive(Clone, Debug, Default)]
+pub struct InitOptions {
+    pub build_mode: Option<BuildMode>,
+    pub server: Option<String>,
+    pub token: Option<String>,
+    pub force: bool,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Command {
     Build,
     Deploy,
+    Watch,
+    Download,
+    Branch,
+    Console,
+    Symbolicate,
+    Size,
+    Package,
+    ConfigShow,
+    Check,
+    Init,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BranchCommand {
+    List,
+    Clone {
+        branch: String,
+        new_name: String,
+    },
+    Delete {
+        branch: String,
+    },
+    Activate {
+        branch: Option<String>,
+        target: ActiveBranch,
+    },
+}
+
+/// Which of the account's active branches to change.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ActiveBranch {
+    World,
+    Sim,
 }
 
 fn app() -> clap::Command {
@@ -40,28 +133,293 @@ fn app() -> clap::Command {
                         .value_name("CONFIG_FILE")
                         .value_parser(clap::value_parser!(PathBuf)),
                 )
+                .arg(
+                    clap::Arg::new("message_format")
+                        .long("message-format")
+                        .help("Print progress and errors as newline-delimited JSON events, moving log messages to stderr")
+                        .num_args(1)
+                        .value_name("FMT")
+                        .value_parser(["human", "json"])
+                        .default_value("human"),
+                )
+                .arg(
+                    clap::Arg::new("strict")
+                        .long("strict")
+                        .help("Treat unused configuration keys as errors")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .subcommand(
+                    clap::Command::new("init")
+                        .about("set up the crate in the current directory for building and deploying with cargo screeps")
+                        .arg(
+                            clap::Arg::new("world")
+                                .long("world")
+                                .help("Set up a Screeps: World bot (the default)")
+                                .action(clap::ArgAction::SetTrue)
+                                .conflicts_with("arena"),
+                        )
+                        .arg(
+                            clap::Arg::new("arena")
+                                .long("arena")
+                                .help("Set up a Screeps: Arena bot")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            clap::Arg::new("server")
+                                .long("server")
+                                .help("Server to upload to, as a hostname or a URL like http://localhost:21025; prompted for if not given")
+                                .num_args(1)
+                                .value_name("SERVER"),
+                        )
+                        .arg(
+                            clap::Arg::new("token")
+                                .long("token")
+                                .help("Auth token to upload with; prompted for if not given")
+                                .num_args(1)
+                                .value_name("TOKEN"),
+                        )
+                        .arg(
+                            clap::Arg::new("force")
+                                .short('f')
+                                .long("force")
+                                .help("Overwrite existing screeps.toml and javascript/main.js")
+                                .action(clap::ArgAction::SetTrue),
+                        ),
+                )
                 .subcommand(
                     clap::Command::new("build")
                         .about("build files, put in target/ in project root"),
                 )
                 .subcommand(
-                    clap::Command::new("deploy")
-                        .about("run specified deploy mode (or the default if none is specified)")
+                    deploy_args(clap::Command::new("deploy"))
+                        .about("run specified deploy modes or groups of modes (or the default if none is specified)")
+                        .arg(
+                            mode_arg()
+                                .help("Mode or group of modes to deploy; can be given more than once")
+                                .action(clap::ArgAction::Append),
+                        )
+                        .arg(
+                            clap::Arg::new("all")
+                                .long("all")
+                                .help("Deploy every mode")
+                                .action(clap::ArgAction::SetTrue)
+                                .conflicts_with("mode"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("watch")
+                        .about("run specified deploy mode (or the default if none is specified), then redeploy whenever sources change")
+                        .arg(mode_arg()),
+                )
+                .subcommand(
+                    clap::Command::new("download")
+                        .about("download the code on a branch of the server of the specified upload mode (or the default if none is specified)")
+                        .arg(mode_arg())
+                        .arg(
+                            clap::Arg::new("branch")
+                                .short('b')
+                                .long("branch")
+                                .help("Branch to download; defaults to the mode's branch")
+                                .num_args(1)
+                                .value_name("BRANCH"),
+                        )
+                        .arg(
+                            clap::Arg::new("out")
+                                .short('o')
+                                .long("out")
+                                .help("Directory to write modules to; defaults to target/screeps-download/<mode>/<branch>")
+                                .num_args(1)
+                                .value_name("DIR")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("branch")
+                        .about("manage code branches on the server of the specified upload mode (or the default if none is specified)")
+                        .arg_required_else_help(true)
+                        // the global --mode counts as an argument, so doesn't show help
+                        .subcommand_required(true)
+                        .arg(mode_arg().global(true))
+                        .subcommand(clap::Command::new("list").about("list branches"))
+                        .subcommand(
+                            clap::Command::new("clone")
+                                .about("create a new branch with a copy of an existing branch's code")
+                                .arg(clap::Arg::new("branch").required(true).value_name("BRANCH"))
+                                .arg(clap::Arg::new("new_name").required(true).value_name("NEW_NAME")),
+                        )
+                        .subcommand(
+                            clap::Command::new("delete")
+                                .about("delete a branch")
+                                .arg(clap::Arg::new("branch").required(true).value_name("BRANCH")),
+                        )
+                        .subcommand(
+                            clap::Command::new("activate")
+                                .about("make a branch the active world (or simulation) branch")
+                                .arg(
+                                    clap::Arg::new("branch")
+                                        .help("Branch to activate; defaults to the mode's branch")
+                                        .value_name("BRANCH"),
+                                )
+                                .arg(
+                                    clap::Arg::new("sim")
+                                        .long("sim")
+                                        .help("Set the active simulation branch instead of the world branch")
+                                        .action(clap::ArgAction::SetTrue),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("console")
+                        .about("stream the console of the server of the specified upload mode (or the default if none is specified), running expressions typed on stdin")
+                        .arg(mode_arg())
+                        .arg(shard_arg()),
+                )
+                .subcommand(
+                    clap::Command::new("symbolicate")
+                        .about("read a stack trace from stdin, replacing wasm function indices with function names from the last build of the specified mode (or the build section if none is specified)")
+                        .arg(mode_arg())
                         .arg(
-                            clap::Arg::new("mode")
-                                .short('m')
-                                .long("mode")
+                            clap::Arg::new("symbols")
+                                .short('s')
+                                .long("symbols")
+                                .help("Symbol map to use instead of the one from the last build")
                                 .num_args(1)
-                                .value_name("DEPLOY_MODE"),
+                                .value_name("SYMBOL_MAP")
+                                .value_parser(clap::value_parser!(PathBuf)),
                         ),
                 )
-                .subcommand(clap::Command::new("copy").about("run the copy deploy mode"))
                 .subcommand(
-                    clap::Command::new("upload").about("run the upload deploy mode"),
+                    clap::Command::new("size")
+                        .about("report the size of the modules the specified mode (or the default if none is specified) would deploy from the last build, and the largest functions and data segments in its wasm")
+                        .arg(mode_arg())
+                        .arg(
+                            clap::Arg::new("json")
+                                .long("json")
+                                .help("Print the report as a JSON size-report event; the same as --message-format json")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            clap::Arg::new("top")
+                                .long("top")
+                                .help("Number of functions and data segments to list for each wasm file")
+                                .num_args(1)
+                                .value_name("N")
+                                .value_parser(clap::value_parser!(usize))
+                                .default_value("20"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("package")
+                        .about("build and write the modules the specified mode (or the default if none is specified) would deploy, with a manifest of how they were built, to a bundle to deploy later with 'deploy --from'")
+                        .arg(mode_arg())
+                        .arg(
+                            clap::Arg::new("out")
+                                .short('o')
+                                .long("out")
+                                .help("File to write the bundle to, as a .tar.gz archive if it ends with .tar.gz or .tgz, or JSON otherwise; defaults to target/screeps-package/<mode>.json")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(no_build_arg()),
+                )
+                .subcommand(
+                    clap::Command::new("config")
+                        .about("inspect the configuration")
+                        .arg_required_else_help(true)
+                        .subcommand_required(true)
+                        .arg(mode_arg().global(true))
+                        .subcommand(
+                            clap::Command::new("show")
+                                .about("print the specified mode (or the default if none is specified) with the modes it extends and the build section merged in"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("check")
+                        .about("validate the configuration: check that every mode can be read, that the paths it uses exist and that its credentials are set"),
+                )
+                .subcommand(
+                    deploy_args(clap::Command::new("copy")).about("run the copy deploy mode"),
+                )
+                .subcommand(
+                    deploy_args(clap::Command::new("upload")).about("run the upload deploy mode"),
                 ),
         )
 }
 
+fn mode_arg() -> clap::Arg {
+    clap::Arg::new("mode")
+        .short('m')
+        .long("mode")
+        .num_args(1)
+        .value_name("DEPLOY_MODE")
+}
+
+fn shard_arg() -> clap::Arg {
+    clap::Arg::new("shard")
+        .long("shard")
+        .help("Shard to run console expressions on; defaults to the mode's shard")
+        .num_args(1)
+        .value_name("SHARD")
+}
+
+fn no_build_arg() -> clap::Arg {
+    clap::Arg::new("no_build")
+        .long("no-build")
+        .help("Use the output of the last build in pkg/ without building again")
+        .action(clap::ArgAction::SetTrue)
+}
+
+/// Adds the arguments shared by all commands which deploy code.
+fn deploy_args(command: clap::Command) -> clap::Command {
+    command
+        .arg(
+            clap::Arg::new("force")
+                .short('f')
+                .long("force")
+                .help("Deploy even if the built files are identical to the last deploy")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("allow_oversize")
+                .long("allow-oversize")
+                .help("Deploy even if the code is over the server's code size limit")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("activate")
+                .long("activate")
+                .help("After uploading, make the uploaded branch the active world (or simulation) branch")
+                .num_args(0..=1)
+                .value_name("TARGET")
+                .value_parser(["world", "sim"])
+                .default_missing_value("world"),
+        )
+        .arg(
+            clap::Arg::new("follow")
+                .long("follow")
+                .help("After uploading, stream the server's console")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(shard_arg())
+        .arg(
+            clap::Arg::new("dry_run")
+                .long("dry-run")
+                .help("Build (unless deploying existing output), then list the modules that would be uploaded or the files that would be copied and pruned, without deploying them")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(no_build_arg())
+        .arg(
+            clap::Arg::new("from")
+                .long("from")
+                .help("Deploy the build output in this directory or .tar.gz archive in place of pkg/, or the bundle written by 'package', without building")
+                .num_args(1)
+                .value_name("DIR|ARCHIVE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("no_build"),
+        )
+}
+
 pub fn setup_cli() -> Result<CliConfig, anyhow::Error> {
     let cargo_args = app().get_matches();
 
@@ -75,21 +433,174 @@ pub fn setup_cli() -> Result<CliConfig, anyhow::Error> {
         _ => log::LevelFilter::Trace,
     };
 
-    fern::Dispatch::new()
+    // `size --json` is kept as a shorthand for the global flag
+    let size_json =
+        matches!(args.subcommand(), Some(("size", size_args)) if size_args.get_flag("json"));
+    let message_format = match args.get_one::<String>("message_format").map(String::as_str) {
+        Some("json") => MessageFormat::Json,
+        _ if size_json => MessageFormat::Json,
+        _ => MessageFormat::Human,
+    };
+
+    let logger = fern::Dispatch::new()
         .level(verbosity)
-        .format(|out, message, record| out.finish(format_args!("{}: {}", record.target(), message)))
-        .chain(io::stdout())
-        .apply()
-        .unwrap();
+        .format(|out, message, record| {
+            // dependencies log requests and responses at debug and trace levels,
+            // which can include credentials
+            let message = message.to_string();
+            out.finish(format_args!(
+                "{}: {}",
+                record.target(),
+                redact::redact(&message)
+            ))
+        });
+    let logger = match message_format {
+        MessageFormat::Human => logger.chain(io::stdout()),
+        MessageFormat::Json => {
+            progress::set_listener(progress::print_json);
+            logger.chain(io::stderr())
+        }
+    };
+    // a program calling `run` may have set up logging already, in which case
+    // messages go to its logger instead
+    if logger.apply().is_err() {
+        debug!("a logger is already set, not setting up logging");
+    }
 
-    let mut mode = match args.subcommand_matches("deploy") {
-        Some(deploy_args) => deploy_args.get_one::<String>("mode").map(Into::into),
+    let deploy_options = match args.subcommand() {
+        Some(("deploy" | "copy" | "upload", deploy_args)) => DeployOptions {
+            force: deploy_args.get_flag("force"),
+            allow_oversize: deploy_args.get_flag("allow_oversize"),
+            activate: deploy_args.get_one::<String>("activate").map(|target| {
+                match target.as_str() {
+                    "sim" => ActiveBranch::Sim,
+                    _ => ActiveBranch::World,
+                }
+            }),
+            follow: deploy_args.get_flag("follow"),
+            shard: deploy_args.get_one::<String>("shard").map(Into::into),
+            dry_run: deploy_args.get_flag("dry_run"),
+            no_build: deploy_args.get_flag("no_build"),
+            from: deploy_args.get_one::<PathBuf>("from").map(Into::into),
+        },
+        Some(("package", package_args)) => DeployOptions {
+            no_build: package_args.get_flag("no_build"),
+            ..Default::default()
+        },
+        _ => DeployOptions::default(),
+    };
+
+    let init_options = match args.subcommand() {
+        Some(("init", init_args)) => InitOptions {
+            build_mode: if init_args.get_flag("arena") {
+                Some(BuildMode::Arena)
+            } else if init_args.get_flag("world") {
+                Some(BuildMode::World)
+            } else {
+                None
+            },
+            server: init_args.get_one::<String>("server").map(Into::into),
+            token: init_args.get_one::<String>("token").map(Into::into),
+            force: init_args.get_flag("force"),
+        },
+        _ => InitOptions::default(),
+    };
+
+    let branch_command = match args.subcommand() {
+        Some(("branch", branch_args)) => Some(match branch_args.subcommand() {
+            Some(("list", _)) => BranchCommand::List,
+            Some(("clone", clone_args)) => BranchCommand::Clone {
+                branch: clone_args.get_one::<String>("branch").unwrap().clone(),
+                new_name: clone_args.get_one::<String>("new_name").unwrap().clone(),
+            },
+            Some(("delete", delete_args)) => BranchCommand::Delete {
+                branch: delete_args.get_one::<String>("branch").unwrap().clone(),
+            },
+            Some(("activate", activate_args)) => BranchCommand::Activate {
+                branch: activate_args.get_one::<String>("branch").map(Into::into),
+                target: if activate_args.get_flag("sim") {
+                    ActiveBranch::Sim
+                } else {
+                    ActiveBranch::World
+                },
+            },
+            other => unreachable!("clap requires a branch subcommand, got {other:?}"),
+        }),
+        _ => None,
+    };
+
+    let (branch, out_dir) = match args.subcommand() {
+        Some(("download", download_args)) => (
+            download_args.get_one::<String>("branch").map(Into::into),
+            download_args.get_one::<PathBuf>("out").map(Into::into),
+        ),
+        _ => (None, None),
+    };
+
+    let out_file = match args.subcommand() {
+        Some(("package", package_args)) => package_args.get_one::<PathBuf>("out").map(Into::into),
+        _ => None,
+    };
+
+    let symbols_path = match args.subcommand() {
+        Some(("symbolicate", symbolicate_args)) => symbolicate_args
+            .get_one::<PathBuf>("symbols")
+            .map(Into::into),
+        _ => None,
+    };
+
+    let top = match args.subcommand() {
+        Some(("size", size_args)) => *size_args.get_one::<usize>("top").unwrap(),
+        _ => Default::default(),
+    };
+
+    let shard = match args.subcommand() {
+        Some((_, subcommand_args)) => subcommand_args
+            .try_get_one::<String>("shard")
+            .ok()
+            .flatten()
+            .map(Into::into),
+        None => None,
+    };
+
+    let mut mode = match args.subcommand() {
+        Some((_, subcommand_args)) => subcommand_args
+            .try_get_one::<String>("mode")
+            .ok()
+            .flatten()
+            .map(Into::into),
         None => None,
     };
 
+    let (deploy_modes, all_modes) = match args.subcommand() {
+        Some(("deploy", deploy_args)) => (
+            deploy_args
+                .get_many::<String>("mode")
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            deploy_args.get_flag("all"),
+        ),
+        _ => (Vec::new(), false),
+    };
+
     let command = match args.subcommand_name() {
         Some("build") => Command::Build,
         Some("deploy") => Command::Deploy,
+        Some("watch") => Command::Watch,
+        Some("download") => Command::Download,
+        Some("branch") => Command::Branch,
+        Some("console") => Command::Console,
+        Some("symbolicate") => Command::Symbolicate,
+        Some("size") => Command::Size,
+        Some("package") => Command::Package,
+        Some("init") => Command::Init,
+        Some("check") => Command::Check,
+        Some("config") => match args.subcommand().and_then(|(_, a)| a.subcommand_name()) {
+            Some("show") => Command::ConfigShow,
+            other => unreachable!("clap requires a config subcommand, got {other:?}"),
+        },
         Some("copy") => {
             mode = Some("copy".to_owned());
             Command::Deploy
@@ -103,7 +614,23 @@ pub fn setup_cli() -> Result<CliConfig, anyhow::Error> {
     let config = CliConfig {
         command,
         config_path: args.get_one::<PathBuf>("config").map(Into::into),
+        deploy_modes: match command {
+            Command::Deploy if deploy_modes.is_empty() => mode.iter().cloned().collect(),
+            _ => deploy_modes,
+        },
         deploy_mode: mode,
+        all_modes,
+        deploy_options,
+        init_options,
+        branch,
+        out_dir,
+        out_file,
+        branch_command,
+        shard,
+        symbols_path,
+        top,
+        strict: args.get_flag("strict"),
+        message_format,
     };
 
     Ok(config)
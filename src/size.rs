@@ -0,0 +1,344 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use log::*;
+use serde::Serialize;
+use wasmparser::{Parser, Payload, TypeRef};
+
+use crate::{
+    error::{self, Error},
+    progress::{self, Event},
+    symbols::{self, SymbolMap},
+};
+
+/// The most code the official server accepts on a branch.
+pub const CODE_SIZE_LIMIT: u64 = 5 * 1024 * 1024;
+
+/// Breakdown of the code that would be deployed, and of what's taking up space
+/// in the wasm modules.
+#[derive(Debug, Serialize)]
+pub struct SizeReport {
+    /// Modules as deployed, largest first.
+    pub modules: Vec<ModuleSize>,
+    pub total_size: u64,
+    pub limit: u64,
+    pub wasm_files: Vec<WasmSize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ModuleSize {
+    pub name: String,
+    pub binary: bool,
+    /// The module's share of the size counted against the limit, after base64
+    /// encoding for binary modules.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WasmSize {
+    pub path: PathBuf,
+    pub size: u64,
+    /// The largest function bodies, largest first.
+    pub functions: Vec<ItemSize>,
+    /// The largest data segments, largest first.
+    pub data_segments: Vec<ItemSize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ItemSize {
+    pub name: String,
+    pub size: u64,
+}
+
+impl SizeReport {
+    /// Builds a report on the given modules, and on up to `top` of the largest
+    /// functions and data segments of each wasm file in `output_dir`.
+    pub fn new(
+        modules: &HashMap<String, serde_json::Value>,
+        output_dir: &Path,
+        top: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let mut wasm_files = Vec::new();
+        if output_dir.is_dir() {
+            for entry in fs::read_dir(output_dir)? {
+                let path = entry?.path();
+                let file_name = path.to_string_lossy();
+                if file_name.ends_with(".wasm") || file_name.ends_with(".wasm.bin") {
+                    wasm_files.push(
+                        WasmSize::read(&path, top)
+                            .with_context(|| format!("analyzing {}", path.display()))?,
+                    );
+                }
+            }
+        }
+
+        Ok(SizeReport {
+            total_size: modules_size(modules),
+            limit: CODE_SIZE_LIMIT,
+            modules: module_sizes(modules),
+            wasm_files,
+        })
+    }
+
+    pub fn print(&self) {
+        println!("modules:");
+        for module in &self.modules {
+            println!(
+                "  {:>10}  {}{}",
+                format_size(module.size),
+                module.name,
+                if module.binary { " (binary)" } else { "" }
+            );
+        }
+        println!(
+            "  {:>10}  total, {:.2}% of {} limit",
+            format_size(self.total_size),
+            self.total_size as f64 / self.limit as f64 * 100.,
+            format_size(self.limit),
+        );
+
+        for wasm in &self.wasm_files {
+            println!();
+            println!(
+                "largest functions in {} ({}):",
+                wasm.path.display(),
+                format_size(wasm.size)
+            );
+            for function in &wasm.functions {
+                println!("  {:>10}  {}", format_size(function.size), function.name);
+            }
+            if !wasm.data_segments.is_empty() {
+                println!("largest data segments:");
+                for segment in &wasm.data_segments {
+                    println!("  {:>10}  {}", format_size(segment.size), segment.name);
+                }
+            }
+        }
+    }
+}
+
+impl WasmSize {
+    fn read(path: &Path, top: usize) -> Result<Self, anyhow::Error> {
+        let wasm = fs::read(path)?;
+
+        // names come from the module itself, or from the symbol map saved when
+        // the name section was stripped
+        let mut symbol_map = SymbolMap::read_wasm(&wasm)?;
+        let symbol_map_path = symbols::symbol_map_path(path);
+        if symbol_map.functions.is_empty() && symbol_map_path.exists() {
+            symbol_map = SymbolMap::read(&symbol_map_path)?;
+        }
+
+        let mut imported_functions = 0;
+        let mut functions = Vec::new();
+        let mut data_segments = Vec::new();
+
+        for payload in Parser::new(0).parse_all(&wasm) {
+            match payload? {
+                Payload::ImportSection(imports) => {
+                    for import in imports.into_imports() {
+                        if let TypeRef::Func(_) | TypeRef::FuncExact(_) = import?.ty {
+                            imported_functions += 1;
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let index = imported_functions + functions.len() as u32;
+                    let name = symbol_map
+                        .function_name(index)
+                        .unwrap_or_else(|| format!("function[{index}]"));
+                    functions.push(ItemSize {
+                        name,
+                        size: body.range().len() as u64,
+                    });
+                }
+                Payload::DataSection(data) => {
+                    for (index, segment) in data.into_iter().enumerate() {
+                        let index = index as u32;
+                        let name = symbol_map
+                            .data
+                            .get(&index)
+                            .map(|name| symbols::demangle(name))
+                            .unwrap_or_else(|| format!("data[{index}]"));
+                        data_segments.push(ItemSize {
+                            name,
+                            size: segment?.data.len() as u64,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for items in [&mut functions, &mut data_segments] {
+            items.sort_by_key(|item| Reverse(item.size));
+            items.truncate(top);
+        }
+
+        Ok(WasmSize {
+            path: path.to_owned(),
+            size: wasm.len() as u64,
+            functions,
+            data_segments,
+        })
+    }
+}
+
+/// The size of a set of modules as the server measures it against the limit:
+/// the length of the modules object of the upload request's JSON body, counted
+/// in UTF-16 code units like JavaScript strings are.
+pub fn modules_size(modules: &HashMap<String, serde_json::Value>) -> u64 {
+    json_length(modules)
+}
+
+/// The size of each module, largest first.
+pub fn module_sizes(modules: &HashMap<String, serde_json::Value>) -> Vec<ModuleSize> {
+    let mut module_sizes: Vec<ModuleSize> = modules
+        .iter()
+        .map(|(name, contents)| ModuleSize {
+            name: name.clone(),
+            binary: !contents.is_string(),
+            size: module_size(contents),
+        })
+        .collect();
+    module_sizes.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+
+    module_sizes
+}
+
+/// The three largest modules, with their sizes, from sizes sorted largest
+/// first.
+fn largest_modules(module_sizes: &[ModuleSize]) -> Vec<(String, u64)> {
+    module_sizes
+        .iter()
+        .take(3)
+        .map(|module| (module.name.clone(), module.size))
+        .collect()
+}
+
+/// The length of a single module's contents in the upload request's JSON body.
+pub fn module_size(contents: &serde_json::Value) -> u64 {
+    json_length(contents)
+}
+
+fn json_length(value: &impl Serialize) -> u64 {
+    serde_json::to_string(value)
+        .expect("expected JSON values to serialize")
+        .encode_utf16()
+        .count() as u64
+}
+
+/// Checks modules about to be deployed against the code size limit, failing
+/// when they're over it unless `allow_oversize` is set.
+pub fn check_limit(
+    modules: &HashMap<String, serde_json::Value>,
+    allow_oversize: bool,
+) -> Result<(), anyhow::Error> {
+    let total_size = modules_size(modules);
+    let module_sizes = module_sizes(modules);
+
+    if total_size > CODE_SIZE_LIMIT {
+        let largest = largest_modules(&module_sizes);
+
+        if !allow_oversize {
+            return Err(Error::SizeLimitExceeded {
+                total_size,
+                limit: CODE_SIZE_LIMIT,
+                largest,
+                budget: false,
+            }
+            .into());
+        }
+        progress::emit(Event::SizeChecked {
+            total_size,
+            limit: CODE_SIZE_LIMIT,
+            modules: module_sizes,
+        });
+        warn!(
+            "largest modules to deploy: {}",
+            error::format_largest(&largest)
+        );
+    } else {
+        progress::emit(Event::SizeChecked {
+            total_size,
+            limit: CODE_SIZE_LIMIT,
+            modules: module_sizes,
+        });
+    }
+
+    Ok(())
+}
+
+/// Checks built modules against the `size_budget` build option.
+pub fn check_budget(
+    modules: &HashMap<String, serde_json::Value>,
+    budget: u64,
+) -> Result<(), anyhow::Error> {
+    let total_size = modules_size(modules);
+    if total_size > budget {
+        return Err(Error::SizeLimitExceeded {
+            total_size,
+            limit: budget,
+            largest: largest_modules(&module_sizes(modules)),
+            budget: true,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.2} MiB", bytes as f64 / 1024. / 1024.)
+    } else if bytes >= 1024 {
+        format!("{:.2} KiB", bytes as f64 / 1024.)
+    } else {
+        format!("{bytes} B")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modules() -> HashMap<String, serde_json::Value> {
+        HashMap::from([
+            ("main".to_owned(), serde_json::json!("x".repeat(100))),
+            (
+                "bot_bg".to_owned(),
+                serde_json::json!({ "binary": "A".repeat(1000) }),
+            ),
+        ])
+    }
+
+    #[test]
+    fn passes_within_budget() {
+        check_budget(&modules(), 10_000).unwrap();
+    }
+
+    #[test]
+    fn fails_over_budget() {
+        let error = check_budget(&modules(), 1000).unwrap_err();
+
+        match Error::find(&error) {
+            Some(Error::SizeLimitExceeded {
+                limit,
+                largest,
+                budget,
+                ..
+            }) => {
+                assert_eq!(*limit, 1000);
+                assert!(*budget);
+                assert_eq!(largest[0].0, "bot_bg");
+            }
+            other => panic!("unexpected error {other:?}"),
+        }
+        assert!(error.to_string().contains("over the size budget of 1000 B"));
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, ensure, Context};
+use log::*;
+use serde::{Deserialize, Serialize};
+use wasmparser::{KnownCustom, Name, Parser, Payload};
+
+/// Names from a wasm module's name section, which let us turn the bare function
+/// indices in stack traces back into function names.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SymbolMap {
+    /// Function names by function index (including imported functions).
+    pub functions: BTreeMap<u32, String>,
+    /// Data segment names by data segment index.
+    #[serde(default)]
+    pub data: BTreeMap<u32, String>,
+}
+
+impl SymbolMap {
+    pub fn read_wasm(wasm: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut map = SymbolMap::default();
+
+        for payload in Parser::new(0).parse_all(wasm) {
+            let Payload::CustomSection(section) = payload? else {
+                continue;
+            };
+            let KnownCustom::Name(names) = section.as_known() else {
+                continue;
+            };
+
+            for name in names {
+                match name? {
+                    Name::Function(functions) => {
+                        for naming in functions {
+                            let naming = naming?;
+                            map.functions.insert(naming.index, naming.name.to_owned());
+                        }
+                    }
+                    Name::Data(data) => {
+                        for naming in data {
+                            let naming = naming?;
+                            map.data.insert(naming.index, naming.name.to_owned());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading symbol map {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("parsing symbol map {}", path.display()))
+    }
+
+    /// The demangled name of a function, if it's known.
+    pub fn function_name(&self, index: u32) -> Option<String> {
+        self.functions.get(&index).map(|name| demangle(name))
+    }
+}
+
+/// Demangles a Rust symbol, leaving anything else as-is.
+pub fn demangle(name: &str) -> String {
+    match rustc_demangle::try_demangle(name) {
+        Ok(demangled) => format!("{demangled:#}"),
+        Err(_) => name.to_owned(),
+    }
+}
+
+/// Where the symbol map for a built wasm file is kept, next to it in the output
+/// directory.
+pub fn symbol_map_path(wasm_path: &Path) -> PathBuf {
+    let file_name = wasm_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let stem = file_name
+        .strip_suffix(".wasm.bin")
+        .or_else(|| file_name.strip_suffix(".wasm"))
+        .unwrap_or(&file_name);
+
+    wasm_path.with_file_name(format!("{stem}.symbols.json"))
+}
+
+/// Saves a symbol map for each wasm file in the build output directory,
+/// optionally removing the name section from the wasm afterwards.
+pub fn write_symbol_maps(output_dir: &Path, strip_name_section: bool) -> Result<(), anyhow::Error> {
+    for entry in fs::read_dir(output_dir)? {
+        let wasm_path = entry?.path();
+        let file_name = wasm_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if !file_name.ends_with(".wasm") && !file_name.ends_with(".wasm.bin") {
+            continue;
+        }
+
+        let wasm = fs::read(&wasm_path)?;
+        let symbol_map = SymbolMap::read_wasm(&wasm)
+            .with_context(|| format!("reading names from {}", wasm_path.display()))?;
+        let symbol_map_path = symbol_map_path(&wasm_path);
+
+        if symbol_map.functions.is_empty() {
+            debug!(
+                "{} has no name section, not writing symbol map",
+                wasm_path.display()
+            );
+            // don't leave a map from an earlier build lying around to give wrong names
+            if symbol_map_path.exists() {
+                fs::remove_file(&symbol_map_path)?;
+            }
+            continue;
+        }
+
+        debug!("writing symbol map to {}", symbol_map_path.display());
+        fs::write(&symbol_map_path, serde_json::to_string(&symbol_map)?)?;
+
+        if strip_name_section {
+            let stripped = strip_names(&wasm)
+                .with_context(|| format!("stripping names from {}", wasm_path.display()))?;
+            info!(
+                "removed name section from {}, saving {} bytes",
+                wasm_path.display(),
+                wasm.len() - stripped.len()
+            );
+            fs::write(&wasm_path, stripped)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies a wasm module, leaving out its `name` custom section.
+fn strip_names(wasm: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    ensure!(
+        wasm.len() >= 8 && wasm.starts_with(b"\0asm"),
+        "not a wasm module"
+    );
+
+    let mut stripped = wasm[..8].to_vec();
+    let mut pos = 8;
+
+    while pos < wasm.len() {
+        let section_start = pos;
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_leb128(wasm, &mut pos)? as usize;
+        let contents_start = pos;
+        let section_end = contents_start
+            .checked_add(size)
+            .filter(|&end| end <= wasm.len())
+            .ok_or_else(|| anyhow!("section at offset {} overruns module", section_start))?;
+
+        let is_name_section = id == 0 && {
+            let name_len = read_leb128(wasm, &mut pos)? as usize;
+            wasm.get(pos..pos + name_len) == Some(b"name")
+        };
+
+        if !is_name_section {
+            stripped.extend_from_slice(&wasm[section_start..section_end]);
+        }
+        pos = section_end;
+    }
+
+    Ok(stripped)
+}
+
+fn read_leb128(bytes: &[u8], pos: &mut usize) -> Result<u32, anyhow::Error> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| anyhow!("unexpected end of module"))?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+
+    Err(anyhow!("invalid LEB128 integer"))
+}
+
+/// Rewrites a stack trace, replacing `wasm-function[N]` frames with the
+/// function's name and demangling any Rust symbols.
+pub fn symbolicate(
+    input: impl BufRead,
+    mut output: impl Write,
+    symbol_map: &SymbolMap,
+) -> Result<(), anyhow::Error> {
+    // V8 frames look like either of
+    //   at wasm://wasm/0123abcd:wasm-function[1234]:0x5678
+    //   at null.<anonymous> (wasm://wasm/0123abcd:wasm-function[1234]:0x5678)
+    let frame = regex::Regex::new(
+        r"at (?:\S+ \()?((?:wasm://wasm/[0-9a-f]+:)?wasm-function\[(\d+)\](?::0x[0-9a-f]+)?)\)?",
+    )
+    .expect("expected pre-set regex to succeed");
+    let mangled = regex::Regex::new(r"\b_(?:ZN[0-9A-Za-z_$.]+E|R[0-9A-Za-z_]+)\b")
+        .expect("expected pre-set regex to succeed");
+
+    for line in input.lines() {
+        let line = line?;
+
+        let line = frame.replace_all(&line, |captures: &regex::Captures| {
+            let location = &captures[1];
+            let name = captures[2]
+                .parse()
+                .ok()
+                .and_then(|index| symbol_map.function_name(index));
+            match name {
+                Some(name) => format!("at {name} ({location})"),
+                None => captures[0].to_owned(),
+            }
+        });
+        let line = mangled.replace_all(&line, |captures: &regex::Captures| demangle(&captures[0]));
+
+        writeln!(output, "{line}")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE_NAMES: usize = 12;
+
+    fn leb128(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    /// A section with its length padded to five bytes, as LLVM writes them.
+    fn padded_section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let len = contents.len();
+        let mut section = vec![id];
+        for shift in [0, 7, 14, 21] {
+            section.push((len >> shift) as u8 & 0x7f | 0x80);
+        }
+        section.push((len >> 28) as u8 & 0x7f);
+        section.extend_from_slice(contents);
+        section
+    }
+
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id];
+        section.extend(leb128(contents.len()));
+        section.extend_from_slice(contents);
+        section
+    }
+
+    fn custom_contents(name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut contents = leb128(name.len());
+        contents.extend_from_slice(name.as_bytes());
+        contents.extend_from_slice(payload);
+        contents
+    }
+
+    /// A mangled name long enough that the name section's length takes more
+    /// than one byte.
+    fn long_name() -> String {
+        let path: String = (0..MODULE_NAMES)
+            .map(|i| format!("8module{i:02}"))
+            .collect();
+        format!("_ZN3bot{path}4tick17h0123456789abcdefE")
+    }
+
+    fn demangled_long_name() -> String {
+        let path: String = (0..MODULE_NAMES)
+            .map(|i| format!("module{i:02}::"))
+            .collect();
+        format!("bot::{path}tick")
+    }
+
+    /// The sections of a module with one function, named in its name section,
+    /// and a large custom section.
+    fn sections() -> Vec<Vec<u8>> {
+        let name = long_name();
+        let mut function_names = vec![1, 0];
+        function_names.extend(leb128(name.len()));
+        function_names.extend_from_slice(name.as_bytes());
+        let mut names = vec![1];
+        names.extend(leb128(function_names.len()));
+        names.extend(function_names);
+
+        vec![
+            section(1, &[1, 0x60, 0, 0]),
+            section(3, &[1, 0]),
+            section(0, &custom_contents("producers", &[b'x'; 200])),
+            padded_section(10, &[1, 2, 0, 0x0b]),
+            section(0, &custom_contents("name", &names)),
+            padded_section(0, &custom_contents("target_features", &[0])),
+        ]
+    }
+
+    fn module(sections: &[Vec<u8>]) -> Vec<u8> {
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        for section in sections {
+            wasm.extend_from_slice(section);
+        }
+        wasm
+    }
+
+    #[test]
+    fn strips_only_the_name_section() {
+        let sections = sections();
+        // the large sections' lengths take more than one byte
+        assert!(sections[2][1] & 0x80 != 0);
+        assert!(sections[4][1] & 0x80 != 0);
+        let wasm = module(&sections);
+
+        let symbol_map = SymbolMap::read_wasm(&wasm).unwrap();
+        assert_eq!(symbol_map.functions[&0], long_name());
+
+        let stripped = strip_names(&wasm).unwrap();
+        let mut kept = sections.clone();
+        kept.remove(4);
+        assert_eq!(stripped, module(&kept));
+        assert!(SymbolMap::read_wasm(&stripped)
+            .unwrap()
+            .functions
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_modules() {
+        let wasm = module(&sections());
+
+        assert!(strip_names(&wasm[..wasm.len() - 1]).is_err());
+        assert!(strip_names(b"\0asm").is_err());
+    }
+
+    #[test]
+    fn symbolicates_stack_traces() {
+        let symbol_map = SymbolMap::read_wasm(&module(&sections())).unwrap();
+        let trace = "\
+panicked at src/lib.rs:10:5
+Error
+    at wasm://wasm/0123abcd:wasm-function[0]:0x5678
+    at null.<anonymous> (wasm://wasm/0123abcd:wasm-function[0]:0x10)
+    at wasm://wasm/0123abcd:wasm-function[7]:0x20
+    at _ZN3bot4tick17h0123456789abcdefE (main.js:3:5)
+";
+
+        let mut output = Vec::new();
+        symbolicate(trace.as_bytes(), &mut output, &symbol_map).unwrap();
+
+        let name = demangled_long_name();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "\
+panicked at src/lib.rs:10:5
+Error
+    at {name} (wasm://wasm/0123abcd:wasm-function[0]:0x5678)
+    at {name} (wasm://wasm/0123abcd:wasm-function[0]:0x10)
+    at wasm://wasm/0123abcd:wasm-function[7]:0x20
+    at bot::tick (main.js:3:5)
+"
+            )
+        );
+    }
+}
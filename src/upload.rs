@@ -3,29 +3,158 @@ use std::{
     fs,
     io::Read,
     path::{Path, PathBuf},
-    time::Duration,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{bail, ensure};
+use anyhow::Context;
 use base64::Engine;
 use log::*;
+use reqwest::{
+    blocking::{RequestBuilder, Response},
+    header::HeaderMap,
+    StatusCode,
+};
 use serde::Serialize;
 
-use crate::config::Authentication;
+use crate::{
+    config::{Authentication, ServerConfiguration},
+    deploy_cache::{self, DeployCache},
+    error::Error,
+    progress::{self, Event},
+    setup::DeployOptions,
+    size,
+};
 
-const CODE_SIZE_LIMIT: u32 = 5 * 1024 * 1024;
+// longest we'll wait for a rate limit to reset before giving up; the official
+// server's code upload limit is per day, so a reset can be hours away
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(5 * 60);
 
+/// Uploads the `.js` and `.wasm` files in `include_files` to a branch on the
+/// server, unless they're identical to the last files uploaded there. With
+/// `options.dry_run`, reports the modules it would upload without sending them.
 pub fn upload(
     root: &Path,
     build_path: &Option<PathBuf>,
     authentication: &Authentication,
+    server: &ServerConfiguration,
     branch: &String,
     include_files: &Vec<PathBuf>,
-    url: &String,
-    http_timeout: Option<u32>,
+    options: &DeployOptions,
+) -> Result<(), anyhow::Error> {
+    let files = collect_modules(root, build_path, include_files)?;
+
+    upload_modules(root, files, authentication, server, branch, options)
+}
+
+/// Uploads modules, as read by [`collect_modules`] or from a bundle, the same
+/// way as [`upload`].
+pub(crate) fn upload_modules(
+    root: &Path,
+    files: HashMap<String, serde_json::Value>,
+    authentication: &Authentication,
+    server: &ServerConfiguration,
+    branch: &String,
+    options: &DeployOptions,
 ) -> Result<(), anyhow::Error> {
+    let url = server.api_url("user/code");
+
+    size::check_limit(&files, options.allows_oversize())?;
+
+    let mut deploy_cache = DeployCache::load(root);
+    let cache_key = deploy_cache::cache_key(&url, branch);
+    let modules_hash = deploy_cache::hash_modules(&files);
+
+    if options.dry_run {
+        progress::emit(Event::UploadPlanned {
+            url,
+            branch: branch.clone(),
+            total_size: size::modules_size(&files),
+            modules: size::module_sizes(&files),
+            unchanged: !options.force && deploy_cache.is_current(&cache_key, &modules_hash),
+        });
+        return Ok(());
+    }
+
+    if !options.force && deploy_cache.is_current(&cache_key, &modules_hash) {
+        progress::emit(Event::UploadSkipped {
+            url,
+            branch: branch.clone(),
+        });
+        return Ok(());
+    }
+
+    progress::emit(Event::UploadStarted {
+        url: url.clone(),
+        branch: branch.clone(),
+    });
+
+    let client = client(server)?;
+
+    #[derive(Serialize)]
+    struct RequestData {
+        modules: HashMap<String, serde_json::Value>,
+        branch: String,
+    }
+
+    let request_data = RequestData {
+        modules: files,
+        branch: branch.clone(),
+    };
+
+    let response = send_with_retries(server, || {
+        authenticate(client.post(&url), authentication).json(&request_data)
+    })?;
+
+    let response_status = response.status();
+    let response_url = response.url().clone();
+    let response_headers = response.headers().clone();
+    let response_text = response.text()?;
+
+    if !response_status.is_success() {
+        return Err(Error::from_status(
+            response_status,
+            response_url.to_string(),
+            response_text,
+        ))
+        .context(format!("uploading to branch '{branch}'"));
+    }
+
+    log_rate_limit(&response_headers);
+
+    debug!("upload finished: {}", response_text);
+
+    let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
+
+    if let Some(s) = response_json.get("error") {
+        return Err(Error::Server {
+            url: response_url.to_string(),
+            message: s.to_string(),
+        })
+        .context(format!("uploading to branch '{branch}'"));
+    }
+
+    if let Err(e) = deploy_cache.record(cache_key, modules_hash) {
+        warn!("failed to record upload in deploy cache: {:#}", e);
+    }
+
+    progress::emit(Event::UploadFinished {
+        url,
+        branch: branch.clone(),
+        status: response_status.as_u16(),
+    });
+
+    Ok(())
+}
+
+/// Reads the `.js` and `.wasm` files in `include_files` into modules named after
+/// the files, the way they're sent to the server.
+pub fn collect_modules(
+    root: &Path,
+    build_path: &Option<PathBuf>,
+    include_files: &Vec<PathBuf>,
+) -> Result<HashMap<String, serde_json::Value>, anyhow::Error> {
     let mut files = HashMap::new();
-    let mut files_total_bytes = 0u32;
 
     for target in include_files {
         let target_dir = build_path
@@ -34,6 +163,14 @@ pub fn upload(
             .unwrap_or_else(|| root.into())
             .join(target);
 
+        if !target_dir.is_dir() {
+            return Err(Error::MissingFiles {
+                path: target_dir,
+                description: "directory of files to deploy doesn't exist".to_owned(),
+            }
+            .into());
+        }
+
         for entry in fs::read_dir(target_dir)? {
             let entry = entry?;
             let path = entry.path();
@@ -45,7 +182,6 @@ pub fn upload(
                         fs::File::open(&path)?.read_to_string(&mut buf)?;
                         buf
                     };
-                    files_total_bytes += data.chars().count() as u32;
                     serde_json::Value::String(data)
                 } else if extension == "wasm" {
                     let data = {
@@ -54,7 +190,6 @@ pub fn upload(
                         buf
                     };
                     let data = base64::engine::general_purpose::STANDARD_NO_PAD.encode(data);
-                    files_total_bytes += data.chars().count() as u32;
                     serde_json::json!({ "binary": data })
                 } else {
                     continue;
@@ -65,80 +200,174 @@ pub fn upload(
         }
     }
 
-    let pct_consumed = files_total_bytes as f64 / CODE_SIZE_LIMIT as f64;
-    let mb_consumed = files_total_bytes as f64 / 1024. / 1024.;
-    if files_total_bytes > CODE_SIZE_LIMIT {
-        warn!(
-            "Files to upload over limit, failure expected! {:.2}MiB of 5MiB limit ({:.2}%)",
-            mb_consumed,
-            pct_consumed * 100.
-        );
-    } else if pct_consumed > 0.9 {
-        warn!(
-            "Files to upload near limit! {:.2}MiB of 5MiB limit ({:.2}%)",
-            mb_consumed,
-            pct_consumed * 100.
-        );
-    } else {
-        info!(
-            "Files to upload consuming {:.2}MiB of 5MiB limit ({:.2}%)",
-            mb_consumed,
-            pct_consumed * 100.
-        );
-    }
+    Ok(files)
+}
 
+pub(crate) fn client(
+    server: &ServerConfiguration,
+) -> Result<reqwest::blocking::Client, anyhow::Error> {
     let client_builder = reqwest::blocking::Client::builder();
-    let client = match http_timeout {
+    let client = match server.http_timeout {
         None => client_builder.build()?,
         Some(value) => client_builder
             .timeout(Duration::from_secs(value as u64))
             .build()?,
     };
 
-    #[derive(Serialize)]
-    struct RequestData {
-        modules: HashMap<String, serde_json::Value>,
-        branch: String,
+    Ok(client)
+}
+
+/// Sends the request built by `request`, retrying when the server is rate
+/// limiting us, returns a server error, or can't be reached.
+///
+/// The last response is returned as-is once `max_attempts` is used up, leaving
+/// the caller to report the failure.
+pub(crate) fn send_with_retries(
+    server: &ServerConfiguration,
+    request: impl Fn() -> RequestBuilder,
+) -> Result<Response, anyhow::Error> {
+    let max_attempts = server.max_attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        let delay = match request().send() {
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+                if !retryable || attempt >= max_attempts {
+                    return Ok(response);
+                }
+
+                let delay = match rate_limit_wait(&response) {
+                    Some(wait) if status == StatusCode::TOO_MANY_REQUESTS => {
+                        if wait > MAX_RATE_LIMIT_WAIT {
+                            return Err(Error::RateLimited {
+                                url: response.url().to_string(),
+                                resets_in: Some(wait),
+                            }
+                            .into());
+                        }
+                        wait
+                    }
+                    _ => backoff(server, attempt),
+                };
+                progress::emit(Event::Retrying {
+                    url: response.url().to_string(),
+                    reason: status.to_string(),
+                    delay,
+                    attempt,
+                    max_attempts,
+                });
+                delay
+            }
+            Err(e) if (e.is_connect() || e.is_timeout()) && attempt < max_attempts => {
+                let delay = backoff(server, attempt);
+                progress::emit(Event::Retrying {
+                    url: e.url().map(ToString::to_string).unwrap_or_default(),
+                    reason: e.to_string(),
+                    delay,
+                    attempt,
+                    max_attempts,
+                });
+                delay
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        thread::sleep(delay);
+        attempt += 1;
     }
+}
 
-    let response = authenticate(client.post(url), authentication)
-        .json(&RequestData {
-            modules: files,
-            branch: branch.clone(),
-        })
-        .send()?;
+fn backoff(server: &ServerConfiguration, attempt: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempt - 1);
+    Duration::from_secs((server.retry_backoff as u64).saturating_mul(factor))
+}
 
+/// How long the server asks us to wait before sending another request, from
+/// either the `Retry-After` or the Screeps `X-RateLimit-Reset` header.
+fn rate_limit_wait(response: &Response) -> Option<Duration> {
+    if let Some(seconds) = header_u64(response.headers(), "Retry-After") {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let reset = rate_limit_reset(response.headers())?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    // wait an extra second so we don't arrive just before the reset
+    Some(reset.saturating_sub(now) + Duration::from_secs(1))
+}
+
+/// The time since the unix epoch at which the current rate limit window ends.
+fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    let reset = header_u64(headers, "X-RateLimit-Reset")?;
+    // the official server sends seconds, but accept milliseconds as well
+    if reset > 1_000_000_000_000 {
+        Some(Duration::from_millis(reset))
+    } else {
+        Some(Duration::from_secs(reset))
+    }
+}
+
+/// Checks that an API request succeeded, returning its parsed JSON response.
+/// `action` describes the request in error messages, such as "deleting branch
+/// 'test'".
+pub(crate) fn api_response(
+    response: Response,
+    action: &str,
+) -> Result<serde_json::Value, anyhow::Error> {
     let response_status = response.status();
     let response_url = response.url().clone();
     let response_text = response.text()?;
 
-    ensure!(
-        response_status.is_success(),
-        "uploading to '{}' failed: {}",
-        response_url,
-        response_text,
-    );
+    if !response_status.is_success() {
+        return Err(Error::from_status(
+            response_status,
+            response_url.to_string(),
+            response_text,
+        ))
+        .context(action.to_owned());
+    }
 
-    debug!("upload finished: {}", response_text);
+    debug!("{} finished: {}", action, response_text);
 
     let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
 
     if let Some(s) = response_json.get("error") {
-        bail!(
-            "error sending to branch '{}' of '{}': {}",
-            branch,
-            response_url,
-            s
-        );
+        return Err(Error::Server {
+            url: response_url.to_string(),
+            message: s.to_string(),
+        })
+        .context(action.to_owned());
     }
 
-    Ok(())
+    Ok(response_json)
+}
+
+/// Reports the rate limit given in the headers of a successful response.
+pub(crate) fn log_rate_limit(headers: &HeaderMap) {
+    let Some(remaining) = header_u64(headers, "X-RateLimit-Remaining") else {
+        return;
+    };
+
+    let resets_in = rate_limit_reset(headers).and_then(|reset| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(reset.saturating_sub(now))
+    });
+
+    progress::emit(Event::RateLimit {
+        remaining,
+        resets_in,
+    });
 }
 
-fn authenticate(
-    request: reqwest::blocking::RequestBuilder,
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+pub(crate) fn authenticate(
+    request: RequestBuilder,
     authentication: &Authentication,
-) -> reqwest::blocking::RequestBuilder {
+) -> RequestBuilder {
     match authentication {
         Authentication::Token { ref auth_token } => request.header("X-Token", auth_token.as_str()),
         Authentication::Basic {
@@ -147,3 +376,182 @@ fn authenticate(
         } => request.basic_auth(username, Some(password)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread::JoinHandle,
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// Starts a server answering one request with each of `responses` in
+    /// turn, which returns when each request arrived once they've all been
+    /// answered.
+    fn serve(responses: Vec<String>) -> (ServerConfiguration, JoinHandle<Vec<Instant>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let received = Instant::now();
+                    // requests here have no body, so stop at the blank line
+                    // after the headers
+                    let mut reader = BufReader::new(&stream);
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 2 {
+                        line.clear();
+                    }
+                    (&stream).write_all(response.as_bytes()).unwrap();
+                    received
+                })
+                .collect()
+        });
+
+        let server = ServerConfiguration {
+            hostname: "127.0.0.1".to_owned(),
+            ssl: false,
+            port,
+            prefix: None,
+            http_timeout: None,
+            max_attempts: 3,
+            retry_backoff: 0,
+            shard: None,
+        };
+
+        (server, handle)
+    }
+
+    fn response(status: &str, headers: &str) -> String {
+        format!("HTTP/1.1 {status}\r\n{headers}Content-Length: 2\r\nConnection: close\r\n\r\n{{}}")
+    }
+
+    fn get(server: &ServerConfiguration) -> Result<Response, anyhow::Error> {
+        let client = reqwest::blocking::Client::new();
+        let url = server.api_url("user/code");
+        send_with_retries(server, || client.get(&url))
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (server, handle) = serve(vec![
+            response("503 Service Unavailable", ""),
+            response("500 Internal Server Error", ""),
+            response("200 OK", ""),
+        ]);
+
+        assert_eq!(get(&server).unwrap().status(), StatusCode::OK);
+        assert_eq!(handle.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn retries_too_many_requests() {
+        let (server, handle) = serve(vec![
+            response("429 Too Many Requests", ""),
+            response("200 OK", ""),
+        ]);
+
+        assert_eq!(get(&server).unwrap().status(), StatusCode::OK);
+        assert_eq!(handle.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (server, handle) = serve(vec![response("400 Bad Request", "")]);
+
+        assert_eq!(get(&server).unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(handle.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let (server, handle) = serve(vec![
+            response("502 Bad Gateway", ""),
+            response("502 Bad Gateway", ""),
+            response("502 Bad Gateway", ""),
+        ]);
+
+        // the last response is left for the caller to report
+        assert_eq!(get(&server).unwrap().status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(handle.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn waits_for_retry_after() {
+        let (server, handle) = serve(vec![
+            response("429 Too Many Requests", "Retry-After: 1\r\n"),
+            response("200 OK", ""),
+        ]);
+
+        assert_eq!(get(&server).unwrap().status(), StatusCode::OK);
+        let received = handle.join().unwrap();
+        assert!(received[1] - received[0] >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn waits_for_rate_limit_reset() {
+        let reset = now_secs() + 1;
+        let (server, handle) = serve(vec![
+            response(
+                "429 Too Many Requests",
+                &format!("X-RateLimit-Reset: {reset}\r\n"),
+            ),
+            response("200 OK", ""),
+        ]);
+
+        assert_eq!(get(&server).unwrap().status(), StatusCode::OK);
+        let received = handle.join().unwrap();
+        assert!(received[1] - received[0] >= Duration::from_secs(1));
+        assert!(now_secs() >= reset);
+    }
+
+    #[test]
+    fn gives_up_on_long_retry_after() {
+        let (server, handle) = serve(vec![response(
+            "429 Too Many Requests",
+            "Retry-After: 3600\r\n",
+        )]);
+
+        let error = get(&server).unwrap_err();
+        assert!(matches!(
+            Error::find(&error),
+            Some(Error::RateLimited {
+                resets_in: Some(wait),
+                ..
+            }) if *wait == Duration::from_secs(3600)
+        ));
+        assert_eq!(handle.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn gives_up_on_distant_rate_limit_reset() {
+        let reset = now_secs() + 24 * 60 * 60;
+        let (server, handle) = serve(vec![response(
+            "429 Too Many Requests",
+            &format!("X-RateLimit-Reset: {reset}\r\n"),
+        )]);
+
+        let error = get(&server).unwrap_err();
+        assert!(matches!(
+            Error::find(&error),
+            Some(Error::RateLimited {
+                resets_in: Some(wait),
+                ..
+            }) if *wait > MAX_RATE_LIMIT_WAIT
+        ));
+        assert_eq!(handle.join().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use log::*;
+use notify::{
+    event::{AccessKind, EventKind},
+    RecursiveMode, Watcher,
+};
+
+use crate::{
+    config::{self, Configuration},
+    progress, run,
+    setup::MessageFormat,
+};
+
+// how long to wait for further changes after the first one before redeploying,
+// so that saving several files at once only triggers a single build
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The set of paths being watched for one deploy mode.
+#[derive(Debug, PartialEq, Eq)]
+struct WatchTargets {
+    // watched recursively; any change inside triggers a redeploy
+    dirs: Vec<PathBuf>,
+    // files are watched through their parent directory, since editors commonly
+    // replace files on save rather than writing to them in place
+    files: Vec<PathBuf>,
+    // build output directory, changes in which are ignored
+    output_dir: PathBuf,
+}
+
+impl WatchTargets {
+    fn new(root: &Path, config_path: &Path, config: &Configuration, mode: Option<&String>) -> Self {
+        let mut build_path = config.build.path.clone();
+        let mut include_files = vec!["pkg".into(), "javascript".into()];
+
+        let mode = mode.or(config.default_deploy_mode.as_ref());
+        if let Some(mode_config) = mode.and_then(|mode| config.modes.get(mode)) {
+            let build = mode_config.build();
+            if build.path.is_some() {
+                build_path = build.path.clone();
+            }
+            include_files = mode_config.include_files().clone();
+        }
+
+        let crate_dir = build_path
+            .map(|p| root.join(p))
+            .unwrap_or_else(|| root.into());
+        let output_dir = crate_dir.join("pkg");
+
+        let mut dirs = vec![crate_dir.join("src")];
+        for target in include_files {
+            let target_dir = crate_dir.join(target);
+            // the build writes into the output directory, so watching it would
+            // retrigger a deploy after every build
+            if target_dir != output_dir {
+                dirs.push(target_dir);
+            }
+        }
+
+        WatchTargets {
+            dirs: dirs.into_iter().map(canonicalize_lossy).collect(),
+            files: vec![
+                canonicalize_lossy(crate_dir.join("Cargo.toml")),
+                canonicalize_lossy(config_path.to_owned()),
+                canonicalize_lossy(config::local_config_path(config_path)),
+            ],
+            output_dir: canonicalize_lossy(output_dir),
+        }
+    }
+
+    fn watch(&self, watcher: &mut impl Watcher) -> Result<(), anyhow::Error> {
+        for dir in &self.dirs {
+            if dir.is_dir() {
+                watcher
+                    .watch(dir, RecursiveMode::Recursive)
+                    .with_context(|| format!("watching {}", dir.display()))?;
+            } else {
+                debug!("not watching {}, no such directory", dir.display());
+            }
+        }
+        for parent in self.file_parents() {
+            watcher
+                .watch(parent, RecursiveMode::NonRecursive)
+                .with_context(|| format!("watching {}", parent.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn unwatch(&self, watcher: &mut impl Watcher) {
+        for path in self
+            .dirs
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(self.file_parents())
+        {
+            // paths which didn't exist when watching started were never watched
+            let _ = watcher.unwatch(path);
+        }
+    }
+
+    fn file_parents(&self) -> impl Iterator<Item = &Path> {
+        let mut parents: Vec<&Path> = self.files.iter().filter_map(|f| f.parent()).collect();
+        parents.dedup();
+        parents.into_iter()
+    }
+
+    fn is_relevant(&self, path: &Path) -> bool {
+        if path.starts_with(&self.output_dir) {
+            return false;
+        }
+        self.files.iter().any(|f| f == path) || self.dirs.iter().any(|d| path.starts_with(d))
+    }
+}
+
+fn canonicalize_lossy(path: PathBuf) -> PathBuf {
+    path.canonicalize().unwrap_or(path)
+}
+
+pub fn watch(
+    root: &Path,
+    config_path: &Path,
+    mut config: Configuration,
+    deploy_mode: Option<String>,
+    strict: bool,
+    message_format: MessageFormat,
+) -> Result<(), anyhow::Error> {
+    let config_path = canonicalize_lossy(config_path.to_owned());
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+
+    let mut targets = WatchTargets::new(root, &config_path, &config, deploy_mode.as_ref());
+    targets.watch(&mut watcher)?;
+
+    loop {
+        match run::run_deploy(
+            root,
+            config.clone(),
+            deploy_mode.clone().into_iter().collect(),
+            &Default::default(),
+            message_format,
+        ) {
+            Ok(()) => info!("deployed, watching for changes..."),
+            Err(e) => {
+                if message_format == MessageFormat::Json {
+                    progress::print_json_error(&e);
+                }
+                error!("deploy failed, watching for changes: {:#}", e)
+            }
+        }
+
+        let changed = wait_for_changes(&rx, &targets)?;
+        debug!("changed files: {:?}", changed);
+
+        let local_config_path = canonicalize_lossy(config::local_config_path(&config_path));
+        if changed.contains(&config_path) || changed.contains(&local_config_path) {
+            match Configuration::read(&config_path, strict) {
+                Ok(new_config) => {
+                    info!("reloaded {}", config_path.display());
+                    config = new_config;
+
+                    let new_targets =
+                        WatchTargets::new(root, &config_path, &config, deploy_mode.as_ref());
+                    if new_targets != targets {
+                        targets.unwatch(&mut watcher);
+                        targets = new_targets;
+                        targets.watch(&mut watcher)?;
+                    }
+                }
+                Err(e) => error!(
+                    "failed to reload {}, keeping previous configuration: {:#}",
+                    config_path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// Blocks until a relevant change happens, then collects further changes until
+/// none have arrived for [`DEBOUNCE`].
+fn wait_for_changes(
+    rx: &mpsc::Receiver<notify::Result<notify::Event>>,
+    targets: &WatchTargets,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut changed = Vec::new();
+
+    while changed.is_empty() {
+        let event = rx.recv().context("file watcher stopped unexpectedly")?;
+        collect_changes(event, targets, &mut changed);
+    }
+
+    loop {
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(event) => collect_changes(event, targets, &mut changed),
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => {
+                bail!("file watcher stopped unexpectedly")
+            }
+        }
+    }
+
+    Ok(changed)
+}
+
+fn collect_changes(
+    event: notify::Result<notify::Event>,
+    targets: &WatchTargets,
+    changed: &mut Vec<PathBuf>,
+) {
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("error watching files: {}", e);
+            return;
+        }
+    };
+
+    if let EventKind::Access(AccessKind::Read | AccessKind::Open(_)) = event.kind {
+        return;
+    }
+
+    for path in event.paths {
+        if targets.is_relevant(&path) && !changed.contains(&path) {
+            changed.push(path);
+        }
+    }
+}
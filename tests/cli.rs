@@ -0,0 +1,102 @@
+//! Runs the CLI against throwaway projects, checking how it handles its
+//! arguments and the files it writes.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command, Output},
+};
+
+const CONFIG: &str = "[local]\ntype = \"copy\"\ndestination = \"out\"\n";
+
+/// Creates an empty directory for a project, named after the test using it.
+fn project_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cargo-screeps-cli-{}-{}", name, process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Creates a project with the given `screeps.toml`.
+fn project(name: &str, config: &str) -> PathBuf {
+    let dir = project_dir(name);
+    fs::write(dir.join("screeps.toml"), config).unwrap();
+    dir
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cargo-screeps"))
+        .current_dir(dir)
+        .arg("screeps")
+        .args(args)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap()
+}
+
+/// Checks the command failed with a clap usage error, rather than a panic.
+fn assert_usage_error(output: &Output) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("requires a subcommand"), "{stderr}");
+}
+
+#[test]
+fn branch_requires_subcommand() {
+    let dir = project("branch-requires-subcommand", CONFIG);
+
+    assert_usage_error(&run(&dir, &["branch", "-m", "local"]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn config_requires_subcommand() {
+    let dir = project("config-requires-subcommand", CONFIG);
+
+    assert_usage_error(&run(&dir, &["config", "-m", "local"]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn check_rejects_groups_of_missing_modes() {
+    let dir = project(
+        "check-rejects-groups-of-missing-modes",
+        &format!("[groups]\nlive = [\"all\"]\nall = [\"local\", \"mmo\"]\n{CONFIG}"),
+    );
+    fs::create_dir_all(dir.join("javascript")).unwrap();
+
+    let output = run(&dir, &["check"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert_eq!(output.status.code(), Some(10), "{output:?}");
+    assert!(
+        stdout.contains("group live includes mmo, which isn't a mode"),
+        "{stdout}"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn init_arena_passes_check() {
+    let dir = project_dir("init-arena-passes-check");
+    fs::write(
+        dir.join("Cargo.toml"),
+        "[package]\nname = \"bot\"\nversion = \"0.1.0\"\n\n[lib]\ncrate-type = [\"cdylib\"]\n",
+    )
+    .unwrap();
+
+    let output = run(&dir, &["init", "--arena", "--token", "abc"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(!dir.join("javascript").exists());
+
+    let output = run(&dir, &["check"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("mode upload: ok"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
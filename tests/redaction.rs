@@ -0,0 +1,155 @@
+//! Runs the CLI at the most verbose log level, checking credentials from the
+//! configuration never show up in its output.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{self, Command, Output},
+    thread,
+};
+
+use base64::Engine;
+
+const TOKEN: &str = "0123456789abcdef-test-token";
+const USERNAME: &str = "bot";
+const PASSWORD: &str = "hunter2-test-password";
+
+/// Creates a project with existing build output and the given `screeps.toml`.
+fn project(name: &str, config: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cargo-screeps-{}-{}", name, process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(dir.join("pkg")).unwrap();
+    fs::create_dir_all(dir.join("javascript")).unwrap();
+    fs::write(
+        dir.join("pkg/bot.js"),
+        "module.exports.loop = function() {};\n",
+    )
+    .unwrap();
+    fs::write(dir.join("pkg/bot_bg.wasm"), b"\0asm\x01\0\0\0").unwrap();
+    fs::write(dir.join("screeps.toml"), config).unwrap();
+    dir
+}
+
+/// Starts a server accepting every code upload, returning its port.
+fn serve() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut content_length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            reader
+                .by_ref()
+                .take(content_length)
+                .read_to_end(&mut Vec::new())
+                .unwrap();
+
+            (&stream)
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\n{\"ok\":1}",
+                )
+                .unwrap();
+        }
+    });
+
+    port
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cargo-screeps"))
+        .current_dir(dir)
+        .args(["screeps", "-vv"])
+        .args(args)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap()
+}
+
+fn assert_redacted(output: &Output) {
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let basic_auth =
+        base64::engine::general_purpose::STANDARD.encode(format!("{USERNAME}:{PASSWORD}"));
+
+    for secret in [TOKEN, PASSWORD, &basic_auth] {
+        assert!(
+            !text.contains(secret),
+            "found '{secret}' in output:\n{text}"
+        );
+    }
+}
+
+#[test]
+fn redacts_auth_token() {
+    let port = serve();
+    let dir = project(
+        "redacts-auth-token",
+        &format!(
+            "[upload]\ntype = \"upload\"\nauth_token = \"{TOKEN}\"\n\
+             hostname = \"127.0.0.1\"\nssl = false\nport = {port}\n"
+        ),
+    );
+
+    let output = run(&dir, &["deploy", "--mode", "upload", "--no-build"]);
+
+    assert!(output.status.success(), "{output:?}");
+    assert_redacted(&output);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn redacts_password() {
+    let port = serve();
+    let dir = project(
+        "redacts-password",
+        &format!(
+            "[upload]\ntype = \"upload\"\nusername = \"{USERNAME}\"\npassword = \"{PASSWORD}\"\n\
+             hostname = \"127.0.0.1\"\nssl = false\nport = {port}\n"
+        ),
+    );
+
+    let output = run(&dir, &["deploy", "--mode", "upload", "--no-build"]);
+
+    assert!(output.status.success(), "{output:?}");
+    assert_redacted(&output);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn redacts_configuration_errors() {
+    let dir = project(
+        "redacts-configuration-errors",
+        &format!(
+            "[upload]\ntype = \"upload\"\nusername = \"{USERNAME}\"\n\
+             password = \"{PASSWORD}\" oops\n"
+        ),
+    );
+
+    let output = run(&dir, &["deploy", "--mode", "upload", "--no-build"]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("TOML parse error"));
+    assert_redacted(&output);
+
+    fs::remove_dir_all(&dir).unwrap();
+}